@@ -1,8 +1,481 @@
-use hyper::Error as HyperError;
+use serde::de::DeserializeOwned;
+use toml;
+use toml::Value;
+use toml::value::Table;
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::io::Error as IoError;
+use std::path::{Path, PathBuf};
 
 ///This trait is used for services that manage configuration through a load method.
 pub trait RssConfigurable {
     /// This method returns a result string that the implementor uses as configuration, possibly
     /// after a deserialization.
-    fn load(&self) -> Result<String, HyperError>;
+    fn load(&self) -> Result<String, ConfigError>;
+
+    /// Loads the configuration and parses it as TOML. Implementors reading a file should
+    /// override it to report the path of the file in errors.
+    fn load_file(&self) -> Result<ConfigFile, ConfigError> {
+        ConfigFile::parse(PathBuf::new(), &self.load()?)
+    }
+
+    /// Loads the configuration and deserializes it as a whole.
+    fn load_as<T: DeserializeOwned>(&self) -> Result<T, ConfigError>
+    where
+        Self: Sized,
+    {
+        self.load_file()?.get()
+    }
+
+    /// Loads the configuration and deserializes its `[name]` section, if any.
+    fn load_section<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, ConfigError>
+    where
+        Self: Sized,
+    {
+        self.load_file()?.section(name)
+    }
+}
+
+/// A parsed TOML configuration, deserialized as a whole or by sections.
+///
+/// ```toml
+/// [server]
+/// bind_port = 8080
+///
+/// [static]
+/// root = "public"
+///
+/// [myapp.cache]
+/// size = 1024
+/// ```
+///
+/// Besides the sections known to the server, such as `[server]` and `[static]`, applications can
+/// define their own, like `[tls]`, `[logging]` or `[myapp.cache]` above, and read them into
+/// their own serde structs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigFile {
+    path: PathBuf,
+    table: Table,
+}
+
+impl ConfigFile {
+    /// Parses `content`, read from `path`.
+    pub fn parse(path: PathBuf, content: &str) -> Result<ConfigFile, ConfigError> {
+        match toml::from_str(content) {
+            Ok(table) => Ok(ConfigFile { path, table }),
+            Err(e) => Err(ConfigError::parse(path, &e)),
+        }
+    }
+
+    /// Returns the path the configuration has been read from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Deserializes the whole configuration.
+    pub fn get<T: DeserializeOwned>(&self) -> Result<T, ConfigError> {
+        Value::Table(self.table.clone())
+            .try_into()
+            .map_err(|e| ConfigError::parse(self.path.clone(), &e))
+    }
+
+    /// Deserializes the `[name]` section, or returns `None` if there is none. Dots in `name`
+    /// separate nested sections, as in `myapp.cache`.
+    pub fn section<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, ConfigError> {
+        match self.table(name) {
+            Some(table) => Value::Table(table.clone())
+                .try_into()
+                .map(Some)
+                .map_err(|e| ConfigError::Parse {
+                    path: self.path.clone(),
+                    line: None,
+                    column: None,
+                    message: format!("[{}]: {}", name, e),
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`section`](#method.section), returning the default value when the section is
+    /// missing.
+    pub fn section_or_default<T>(&self, name: &str) -> Result<T, ConfigError>
+    where
+        T: DeserializeOwned + Default,
+    {
+        self.section(name).map(Option::unwrap_or_default)
+    }
+
+    /// Returns the configuration read from the same file, with `table` as content.
+    pub(crate) fn with_table(self, table: Table) -> ConfigFile {
+        ConfigFile { table, ..self }
+    }
+
+    /// Returns the `[name]` table, the whole configuration if `name` is empty.
+    pub(crate) fn table(&self, name: &str) -> Option<&Table> {
+        if name.is_empty() {
+            return Some(&self.table);
+        }
+        let mut table = &self.table;
+        for name in name.split('.') {
+            table = table.get(name)?.as_table()?;
+        }
+        Some(table)
+    }
+}
+
+/// What to do when a configuration file is missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingConfig {
+    /// Write the default configuration to the file, then use it. The directory of the file is
+    /// not created. This is the default.
+    Create,
+    /// Use the default configuration, without writing it. Suits read-only filesystems.
+    Defaults,
+    /// Fail with a [`ConfigError::Io`](enum.ConfigError.html#variant.Io) error.
+    Fail,
+}
+
+impl Default for MissingConfig {
+    fn default() -> MissingConfig {
+        MissingConfig::Create
+    }
+}
+
+/// An invalid value of a configuration field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldError {
+    /// Name of the field, like `bind_port` or `virtual_hosts.sites.main`
+    pub field: String,
+    /// What is wrong with the value
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new<F: Into<String>, M: Into<String>>(field: F, message: M) -> FieldError {
+        FieldError {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Error raised while loading a configuration file.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read or written.
+    Io { path: PathBuf, source: IoError },
+    /// The file is not valid TOML, or doesn't have the expected structure. `line` and `column`
+    /// start from 1.
+    Parse {
+        path: PathBuf,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+    /// Some fields have invalid values.
+    Invalid { path: PathBuf, errors: Vec<FieldError> },
+    /// An override, such as a command line argument, is malformed or has an invalid value.
+    /// `source` names it, like `RSS_BIND_PORT` or `--bind-port`.
+    Override { source: String, message: String },
+}
+
+impl ConfigError {
+    /// Builds the error raised when `path` can't be deserialized.
+    pub fn parse(path: PathBuf, error: &toml::de::Error) -> ConfigError {
+        let (line, column) = match error.line_col() {
+            Some((line, column)) => (Some(line + 1), Some(column + 1)),
+            None => (None, None),
+        };
+        ConfigError::Parse {
+            path,
+            line,
+            column,
+            message: error.to_string(),
+        }
+    }
+
+    /// Returns the path of the file the error comes from, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            ConfigError::Io { ref path, .. }
+            | ConfigError::Parse { ref path, .. }
+            | ConfigError::Invalid { ref path, .. } => Some(path),
+            ConfigError::Override { .. } => None,
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ConfigError::Io { ref source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io {
+                ref path,
+                ref source,
+            } => write!(f, "{}: {}", path.display(), source),
+            ConfigError::Parse {
+                ref path,
+                line,
+                column,
+                ref message,
+            } => {
+                // Configurations not read from a file have an empty path
+                let mut location = path.display().to_string();
+                if let (Some(line), Some(column)) = (line, column) {
+                    location = format!("{}:{}:{}", location, line, column);
+                }
+                match location.trim_start_matches(':') {
+                    "" => write!(f, "{}", message),
+                    location => write!(f, "{}: {}", location, message),
+                }
+            }
+            ConfigError::Invalid {
+                ref path,
+                ref errors,
+            } => {
+                write!(f, "{}: invalid configuration", path.display())?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+            ConfigError::Override {
+                ref source,
+                ref message,
+            } => write!(f, "{}: {}", source, message),
+        }
+    }
+}
+
+/// A layer of configuration values overriding the ones of a configuration file, such as
+/// environment variables or command line arguments.
+///
+/// Values are keyed by their dotted path in the file, like `server.bind_port` or
+/// `static.root`, and parsed as TOML values when possible (`8080`, `true`, `["a", "b"]`), as
+/// strings otherwise (`0.0.0.0`).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigOverrides {
+    values: Vec<OverrideValue>,
+}
+
+/// A value of a [`ConfigOverrides`](struct.ConfigOverrides.html) layer.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct OverrideValue {
+    /// Where the value comes from, like `RSS_BIND_PORT` or `--bind-port`, for errors
+    pub source: String,
+    /// Dotted path of the value, like `bind_port`
+    pub key: String,
+    pub value: Value,
+}
+
+impl ConfigOverrides {
+    /// Creates an empty layer.
+    pub fn new() -> ConfigOverrides {
+        ConfigOverrides::default()
+    }
+
+    /// Reads the environment variables starting with `RSS_`, see
+    /// [`from_vars`](#method.from_vars).
+    pub fn from_env() -> ConfigOverrides {
+        ConfigOverrides::from_vars("RSS_", env::vars())
+    }
+
+    /// Reads the variables whose name starts with `prefix`. The rest of the name is lowercased,
+    /// and `__` separates nested keys: with the `RSS_` prefix, `RSS_BIND_PORT` sets `bind_port`
+    /// and `RSS_STATIC__ROOT` sets `static.root`.
+    pub fn from_vars<I>(prefix: &str, vars: I) -> ConfigOverrides
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut overrides = ConfigOverrides::new();
+        for (name, value) in vars {
+            if let Some(key) = name.strip_prefix(prefix) {
+                if !key.is_empty() {
+                    let key = key.to_lowercase().replace("__", ".");
+                    overrides = overrides.with_source(&name, &key, &value);
+                }
+            }
+        }
+        overrides
+    }
+
+    /// Reads command line arguments such as `--bind-port=8080`, `--bind-port 8080` or
+    /// `--static.root public`. Dashes in names stand for underscores, and dots separate
+    /// nested keys.
+    pub fn from_args<I>(args: I) -> Result<ConfigOverrides, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut overrides = ConfigOverrides::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) if !name.is_empty() && !name.starts_with('=') => name,
+                _ => {
+                    return Err(ConfigError::Override {
+                        source: arg.clone(),
+                        message: String::from("expected an option like --name=value"),
+                    })
+                }
+            };
+            let (name, value) = match name.find('=') {
+                Some(ix) => (&name[..ix], name[ix + 1..].to_owned()),
+                None => match args.next() {
+                    Some(value) => (name, value),
+                    None => {
+                        return Err(ConfigError::Override {
+                            source: arg.clone(),
+                            message: String::from("missing value"),
+                        })
+                    }
+                },
+            };
+            let source = format!("--{}", name);
+            overrides = overrides.with_source(&source, &name.replace('-', "_"), &value);
+        }
+        Ok(overrides)
+    }
+
+    /// Sets `key` to `value`.
+    pub fn with(self, key: &str, value: &str) -> ConfigOverrides {
+        self.with_source(key, key, value)
+    }
+
+    /// Sets `key` to `value`, which comes from `source`.
+    fn with_source(mut self, source: &str, key: &str, value: &str) -> ConfigOverrides {
+        self.values.push(OverrideValue {
+            source: source.to_owned(),
+            key: key.to_owned(),
+            value: parse_value(value),
+        });
+        self
+    }
+
+    /// Returns the values of the layer, in order.
+    pub(crate) fn values(&self) -> &[OverrideValue] {
+        &self.values
+    }
+
+    /// Returns `true` if the layer has no values.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Sets the values of the layer in `table`, creating the intermediate tables.
+    pub fn apply(&self, table: &mut Table) {
+        for value in &self.values {
+            set_value(table, &value.key, value.value.clone());
+        }
+    }
+}
+
+/// Sets the value at the dotted path `key` of `table`, creating the intermediate tables.
+pub(crate) fn set_value(table: &mut Table, key: &str, value: Value) {
+    let mut table = table;
+    let mut names: Vec<&str> = key.split('.').collect();
+    let last = names.pop().unwrap_or("");
+    for name in names {
+        let entry = table
+            .entry(name.to_owned())
+            .or_insert_with(|| Value::Table(Table::new()));
+        if !entry.is_table() {
+            *entry = Value::Table(Table::new());
+        }
+        table = match *entry {
+            Value::Table(ref mut table) => table,
+            _ => unreachable!(),
+        };
+    }
+    table.insert(last.to_owned(), value);
+}
+
+/// Parses an override as a TOML value, or takes it as a string.
+fn parse_value(value: &str) -> Value {
+    match toml::from_str::<Table>(&format!("value = {}", value)) {
+        Ok(mut table) => table.remove("value").unwrap_or_else(|| Value::String(value.to_owned())),
+        Err(_) => Value::String(value.to_owned()),
+    }
+}
+
+/// Merges `overlay` into `base`: tables are merged key by key, other values replaced.
+pub(crate) fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(&mut Value::Table(ref mut base)), Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StringConfigurator(&'static str);
+
+    impl RssConfigurable for StringConfigurator {
+        fn load(&self) -> Result<String, ConfigError> {
+            Ok(String::from(self.0))
+        }
+    }
+
+    #[derive(Deserialize, Debug, Default, PartialEq)]
+    struct Cache {
+        size: usize,
+        #[serde(default)]
+        persistent: bool,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct App {
+        name: String,
+        cache: Cache,
+    }
+
+    #[test]
+    fn test_config_sections() {
+        let configurator = StringConfigurator(
+            "[app]\nname = \"feeds\"\n\n[app.cache]\nsize = 64\n\n[logging]\nlevel = 3\n",
+        );
+        let app: App = configurator.load_section("app").unwrap().unwrap();
+        assert_eq!(app.name, "feeds");
+        assert_eq!(app.cache, Cache { size: 64, persistent: false });
+
+        let file = configurator.load_file().unwrap();
+        let cache: Option<Cache> = file.section("app.cache").unwrap();
+        assert_eq!(cache, Some(Cache { size: 64, persistent: false }));
+        assert_eq!(file.section::<Cache>("tls").unwrap(), None);
+        assert_eq!(file.section_or_default::<Cache>("tls").unwrap(), Cache::default());
+
+        let error = file.section::<Cache>("logging").unwrap_err();
+        assert!(error.to_string().starts_with("[logging]: missing field `size`"), "{}", error);
+
+        #[derive(Deserialize)]
+        struct Whole {
+            app: App,
+        }
+        let whole: Whole = configurator.load_as().unwrap();
+        assert_eq!(whole.app.cache.size, 64);
+    }
 }
@@ -1,7 +1,9 @@
 use std::error::Error;
 use std::convert::From;
 use hyper::StatusCode;
+use hyper::header::{Header, Headers};
 use hyper::server::Request as HyperRequest;
+use services::RouteContext;
 
 use std::fmt;
 
@@ -11,7 +13,17 @@ pub struct HttpError {
     pub request: HyperRequest,
     /// HTTP status code
     pub status_code: StatusCode,
+    /// Context of the request. [`RouterService`](struct.RouterService.html) fills it with the
+    /// context the request was routed with before calling the error handler.
+    pub context: RouteContext,
+    /// Headers to send along with the error response, such as `WWW-Authenticate`, `Retry-After`
+    /// or `Allow`. [`RouterService`](struct.RouterService.html) adds them to the response of
+    /// the error handler, unless it already has them.
+    pub headers: Headers,
     description: String,
+    message: Option<String>,
+    details: Vec<(String, String)>,
+    source: Option<Box<dyn Error>>,
 }
 
 impl HttpError {
@@ -26,15 +38,63 @@ impl HttpError {
         HttpError {
             request,
             status_code,
+            context: RouteContext::new(),
+            headers: Headers::new(),
             description: Self::reason(status_code),
+            message: None,
+            details: Vec::new(),
+            source: None,
         }
     }
+
+    /// Sets the error that caused this one, returned by `Error::source`.
+    pub fn with_source<E: Error + 'static>(mut self, source: E) -> HttpError {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Adds a header to the error response.
+    pub fn with_header<H: Header>(mut self, header: H) -> HttpError {
+        self.headers.set(header);
+        self
+    }
+
+    /// Sets the message explaining the error to the client.
+    pub fn with_message<S: Into<String>>(mut self, message: S) -> HttpError {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// Adds a structured detail, such as the name of an invalid field, to the error.
+    pub fn with_detail<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> HttpError {
+        self.details.push((name.into(), value.into()));
+        self
+    }
+
+    /// Returns the description of the error, the reason phrase of its status code.
+    pub fn description(&self) -> &str {
+        self.description.as_str()
+    }
+
+    /// Returns the message explaining the error to the client, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// Returns the structured details of the error, in the order they have been added.
+    pub fn details(&self) -> &[(String, String)] {
+        &self.details
+    }
 }
 
 impl Error for HttpError {
     fn description(&self) -> &str {
         self.description.as_str()
     }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref()
+    }
 }
 
 impl fmt::Display for HttpError {
@@ -44,6 +104,47 @@ impl fmt::Display for HttpError {
             "{} - {}",
             self.status_code.as_u16(),
             self.description.to_owned()
-        )
+        )?;
+        if let Some(ref message) = self.message {
+            write!(f, ": {}", message)?;
+        }
+        if let Some(ref source) = self.source {
+            write!(f, " ({})", source)?;
+        }
+        Ok(())
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+    use hyper::header::Allow;
+    use std::io;
+
+    #[test]
+    fn test_http_error() {
+        let uri = "https://www.rss-server.org/".parse().unwrap();
+        let source = io::Error::new(io::ErrorKind::Other, "disk on fire");
+        let req = HyperRequest::new(Method::Post, uri);
+        let http_error = HttpError::new(req, StatusCode::MethodNotAllowed)
+            .with_message("Read only")
+            .with_detail("resource", "users")
+            .with_header(Allow(vec![Method::Get]))
+            .with_source(source);
+
+        assert_eq!(http_error.description(), "Method Not Allowed");
+        assert_eq!(http_error.message(), Some("Read only"));
+        assert_eq!(
+            http_error.details(),
+            &[(String::from("resource"), String::from("users"))]
+        );
+        assert_eq!(http_error.headers.get_raw("Allow").unwrap(), "GET");
+        assert_eq!(http_error.source().unwrap().to_string(), "disk on fire");
+        assert_eq!(
+            http_error.to_string(),
+            "405 - Method Not Allowed: Read only (disk on fire)"
+        );
     }
 }
@@ -2,12 +2,40 @@
 
 extern crate futures;
 extern crate tokio_core;
-extern crate tokio_pool;
 
 extern crate hyper;
+#[macro_use]
+extern crate log;
+extern crate mime_guess;
+extern crate percent_encoding;
+
+extern crate serde;
+extern crate signal_hook;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 
 mod errors;
 pub use errors::HttpError;
 
 mod services;
-pub use services::{ErrorHandler, ResponseFuture, Router, RouterService, RssService};
+pub use services::{range_response, ByteRanges, ChunkStream, DefaultErrorHandler, ETagStrategy,
+                   ErrorHandler, Middleware, MiddlewareRouter, MountRouter, Negotiated, Negotiator,
+                   OriginalUri, Params, PatternHandler, PatternRouter, RangeSource,
+                   ResponseFuture, RouteContext, Router, RouterService, RssService, StaticConfig,
+                   StaticRouterService, SymlinkPolicy, VirtualHostService, VirtualHostsConfig};
+
+mod config;
+pub use config::{ConfigError, ConfigFile, ConfigOverrides, FieldError, MissingConfig,
+                 RssConfigurable};
+
+mod pool;
+
+mod reload;
+pub use reload::ConfigHandle;
+
+mod shutdown;
+pub use shutdown::ShutdownHandle;
+
+mod server;
+pub use server::{HttpServer, RssHttpServer, HTTP_SERVER_CONFIG_STR};
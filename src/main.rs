@@ -0,0 +1,62 @@
+//! Command line tool of rss-server.
+//!
+//! ```text
+//! rss-server init-config [--force] [CONFIG_PATH]
+//! ```
+
+extern crate rss_server;
+
+use rss_server::RssHttpServer;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "usage: rss-server init-config [--force] [CONFIG_PATH]
+
+Commands:
+    init-config    Writes the default http-server.toml to CONFIG_PATH, the current directory
+                   if omitted
+
+Options:
+    --force        Replaces an existing http-server.toml";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        Some("init-config") => init_config(args),
+        Some("-h") | Some("--help") => println!("{}", USAGE),
+        _ => usage_error(None),
+    }
+}
+
+/// Writes the default configuration where the arguments tell.
+fn init_config<I: Iterator<Item = String>>(args: I) {
+    let mut overwrite = false;
+    let mut config_path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--force" => overwrite = true,
+            _ if config_path.is_none() && !arg.starts_with('-') => {
+                config_path = Some(PathBuf::from(arg))
+            }
+            _ => usage_error(Some(&arg)),
+        }
+    }
+    let config_path = config_path.unwrap_or_else(|| PathBuf::from("."));
+    match RssHttpServer::init_config(&config_path, overwrite) {
+        Ok(filename) => println!("wrote {}", filename.display()),
+        Err(e) => {
+            eprintln!("rss-server: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage_error(arg: Option<&str>) -> ! {
+    if let Some(arg) = arg {
+        eprintln!("rss-server: unexpected argument {}\n", arg);
+    }
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,337 @@
+use futures::prelude::*;
+use futures::future;
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::task::{self, Task};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream as StdTcpStream};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread::{Builder as ThreadBuilder, JoinHandle};
+use std::time::Duration;
+
+use hyper::Error as HyperError;
+use hyper::server::{Connection, Http, Request as HyperRequest, Response as HyperResponse,
+                    Service as HyperService};
+
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle, Timeout};
+
+use reload::ConfigHandle;
+use shutdown::ShutdownSignal;
+
+struct Worker {
+    sender: UnboundedSender<(StdTcpStream, SocketAddr)>,
+    thread: JoinHandle<()>,
+}
+
+/// A pool of reactor threads. Accepted connections are handed to the workers in round-robin
+/// order, and every worker serves them with the service it has built through the shared factory.
+/// Workers build their service again when the configuration is reloaded.
+pub(crate) struct WorkerPool {
+    workers: Vec<Worker>,
+    next: usize,
+}
+
+impl WorkerPool {
+    /// Spawns `num_workers` threads, each one running its own `Core`.
+    ///
+    /// `new_service` is invoked once per worker, and again for the next connections after every
+    /// reload of `config`, inside the worker thread, so the resulting service does not need to
+    /// be `Send`.
+    ///
+    /// When the pool is [joined](#method.join) every worker stops taking new connections and
+    /// waits at most `shutdown_timeout` seconds for its in-flight connections before exiting.
+    pub fn new<F, S>(
+        num_workers: usize,
+        http: &Http,
+        new_service: Arc<F>,
+        config: ConfigHandle,
+    ) -> io::Result<WorkerPool>
+    where
+        F: Fn(&Handle) -> S + Send + Sync + 'static,
+        S: HyperService<Request = HyperRequest, Response = HyperResponse, Error = HyperError>
+            + 'static,
+    {
+        let mut workers = Vec::with_capacity(num_workers);
+        for ix in 0..num_workers {
+            let (sender, receiver) = unbounded();
+            let http = http.clone();
+            let new_service = Arc::clone(&new_service);
+            let config = config.clone();
+            let thread = ThreadBuilder::new()
+                .name(format!("rss-server-worker-{}", ix))
+                .spawn(move || {
+                    let mut core = match Core::new() {
+                        Ok(core) => core,
+                        Err(e) => {
+                            error!("worker {} could not create its reactor: {}", ix, e);
+                            return;
+                        }
+                    };
+                    let handle = core.handle();
+                    let mut generation = config.generation();
+                    let mut service = Rc::new(new_service(&handle));
+                    let tracker = Rc::new(RefCell::new(Tracker::default()));
+
+                    let connections = receiver.for_each(|(socket, addr)| {
+                        if config.generation() != generation {
+                            generation = config.generation();
+                            debug!("worker {} rebuilding its service", ix);
+                            service = Rc::new(new_service(&handle));
+                        }
+                        match TcpStream::from_stream(socket, &handle) {
+                            Ok(socket) => {
+                                let connection = TrackedConnection::new(
+                                    http.serve_connection(socket, Rc::clone(&service)),
+                                    &tracker,
+                                ).map_err(move |e| error!("connection error ({}): {}", addr, e));
+                                handle.spawn(connection);
+                            }
+                            Err(e) => error!("could not register connection ({}): {}", addr, e),
+                        }
+                        future::ok(())
+                    });
+                    let _ = core.run(connections);
+
+                    // The pool has been joined: let in-flight connections finish their current
+                    // request, then exit. Connections still open after the grace timeout are
+                    // dropped together with the reactor.
+                    Tracker::start_draining(&tracker);
+                    let grace_timeout = Duration::from_secs(config.current().shutdown_timeout);
+                    let drained = Drained {
+                        tracker: Rc::clone(&tracker),
+                    };
+                    let timeout = match Timeout::new(grace_timeout, &handle) {
+                        Ok(timeout) => timeout,
+                        Err(e) => {
+                            error!("worker {} could not start its drain timeout: {}", ix, e);
+                            return;
+                        }
+                    };
+                    match core.run(drained.select2(timeout)) {
+                        Ok(future::Either::A(_)) => debug!("worker {} drained", ix),
+                        Ok(future::Either::B(_)) => warn!(
+                            "worker {}: {} connections still open after {:?}, dropping them",
+                            ix,
+                            tracker.borrow().connections.len(),
+                            grace_timeout
+                        ),
+                        Err(_) => error!("worker {} failed while draining connections", ix),
+                    }
+                })?;
+            workers.push(Worker { sender, thread });
+        }
+        Ok(WorkerPool { workers, next: 0 })
+    }
+
+    /// Hands an accepted connection to the next worker.
+    pub fn dispatch(&mut self, socket: StdTcpStream, addr: SocketAddr) {
+        let ix = self.next;
+        self.next = (self.next + 1) % self.workers.len();
+        if self.workers[ix].sender.unbounded_send((socket, addr)).is_err() {
+            error!("worker {} is gone, dropping connection from {}", ix, addr);
+        }
+    }
+
+    /// Stops feeding the workers and waits for their threads to exit.
+    pub fn join(self) {
+        let (senders, threads): (Vec<_>, Vec<_>) = self.workers
+            .into_iter()
+            .map(|worker| (worker.sender, worker.thread))
+            .unzip();
+        drop(senders);
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("a worker thread panicked");
+            }
+        }
+    }
+}
+
+/// Book-keeping of the connections served by a single worker.
+#[derive(Default)]
+struct Tracker {
+    next_id: usize,
+    /// Open connections, with the task that last polled them
+    connections: HashMap<usize, Option<Task>>,
+    draining: bool,
+    /// Task waiting for `connections` to become empty
+    blocker: Option<Task>,
+}
+
+impl Tracker {
+    /// Marks the worker as draining and wakes every connection up, so that idle keep-alive
+    /// connections get closed right away.
+    fn start_draining(tracker: &Rc<RefCell<Tracker>>) {
+        let mut tracker = tracker.borrow_mut();
+        tracker.draining = true;
+        for task in tracker.connections.values().filter_map(|task| task.as_ref()) {
+            task.notify();
+        }
+    }
+}
+
+/// A hyper `Connection` registered in a [`Tracker`]. Once the worker is draining, keep-alive is
+/// disabled so the connection closes after its current response.
+struct TrackedConnection<S>
+where
+    S: HyperService<Request = HyperRequest, Response = HyperResponse, Error = HyperError>,
+{
+    id: usize,
+    connection: Connection<TcpStream, Rc<S>>,
+    tracker: Rc<RefCell<Tracker>>,
+    keep_alive_disabled: bool,
+}
+
+impl<S> TrackedConnection<S>
+where
+    S: HyperService<Request = HyperRequest, Response = HyperResponse, Error = HyperError>,
+{
+    fn new(
+        connection: Connection<TcpStream, Rc<S>>,
+        tracker: &Rc<RefCell<Tracker>>,
+    ) -> TrackedConnection<S> {
+        let id = {
+            let mut tracker = tracker.borrow_mut();
+            let id = tracker.next_id;
+            tracker.next_id = tracker.next_id.wrapping_add(1);
+            tracker.connections.insert(id, None);
+            id
+        };
+        TrackedConnection {
+            id,
+            connection,
+            tracker: Rc::clone(tracker),
+            keep_alive_disabled: false,
+        }
+    }
+}
+
+impl<S> Future for TrackedConnection<S>
+where
+    S: HyperService<Request = HyperRequest, Response = HyperResponse, Error = HyperError>
+        + 'static,
+{
+    type Item = ();
+    type Error = HyperError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let draining = {
+            let mut tracker = self.tracker.borrow_mut();
+            tracker.connections.insert(self.id, Some(task::current()));
+            tracker.draining
+        };
+        if draining && !self.keep_alive_disabled {
+            self.keep_alive_disabled = true;
+            self.connection.disable_keep_alive();
+        }
+        self.connection.poll()
+    }
+}
+
+impl<S> Drop for TrackedConnection<S>
+where
+    S: HyperService<Request = HyperRequest, Response = HyperResponse, Error = HyperError>,
+{
+    fn drop(&mut self) {
+        let mut tracker = self.tracker.borrow_mut();
+        tracker.connections.remove(&self.id);
+        if tracker.connections.is_empty() {
+            if let Some(blocker) = tracker.blocker.take() {
+                blocker.notify();
+            }
+        }
+    }
+}
+
+/// Future resolving once every connection of a [`Tracker`] has been closed.
+struct Drained {
+    tracker: Rc<RefCell<Tracker>>,
+}
+
+impl Future for Drained {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut tracker = self.tracker.borrow_mut();
+        if tracker.connections.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            tracker.blocker = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Future that accepts connections on `listener` and hands them to a [`WorkerPool`], until
+/// `shutdown` resolves.
+///
+/// Transient accept errors (e.g. too many open files) are logged and accepting is paused for a
+/// second instead of stopping the server.
+pub(crate) struct Acceptor<'a> {
+    listener: TcpListener,
+    pool: &'a mut WorkerPool,
+    shutdown: ShutdownSignal,
+    handle: Handle,
+    timeout: Option<Timeout>,
+}
+
+impl<'a> Acceptor<'a> {
+    pub fn new(
+        listener: TcpListener,
+        pool: &'a mut WorkerPool,
+        shutdown: ShutdownSignal,
+        handle: &Handle,
+    ) -> Acceptor<'a> {
+        Acceptor {
+            listener,
+            pool,
+            shutdown,
+            handle: handle.clone(),
+            timeout: None,
+        }
+    }
+}
+
+impl<'a> Future for Acceptor<'a> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Ok(Async::Ready(())) = self.shutdown.poll() {
+            return Ok(Async::Ready(()));
+        }
+        if let Some(mut timeout) = self.timeout.take() {
+            if let Async::NotReady = timeout.poll()? {
+                self.timeout = Some(timeout);
+                return Ok(Async::NotReady);
+            }
+        }
+        loop {
+            match self.listener.accept_std() {
+                Ok((socket, addr)) => self.pool.dispatch(socket, addr),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(ref e) if is_connection_error(e) => debug!("accepted connection failed: {}", e),
+                Err(e) => {
+                    error!("accept error, pausing for 1s: {}", e);
+                    self.timeout = Some(Timeout::new(Duration::from_secs(1), &self.handle)?);
+                    return self.poll();
+                }
+            }
+        }
+    }
+}
+
+/// Errors that concern a single accepted connection rather than the listener itself.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
@@ -0,0 +1,288 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{Builder as ThreadBuilder, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use serde::de::DeserializeOwned;
+use toml;
+use toml::Value;
+
+use config::{ConfigError, ConfigFile, ConfigOverrides, MissingConfig};
+use server::RssServerConfig;
+use services::VirtualHostsConfig;
+
+/// Where the configuration is read from.
+struct Source {
+    config_path: PathBuf,
+    missing: MissingConfig,
+    overrides: Vec<ConfigOverrides>,
+}
+
+struct Shared {
+    config: RwLock<Arc<RssServerConfig>>,
+    file: RwLock<Arc<ConfigFile>>,
+    generation: AtomicUsize,
+    /// Locked for the whole reload, so that concurrent reloads don't interleave
+    source: Mutex<Source>,
+}
+
+/// A `ConfigHandle` gives access to the configuration of an
+/// [`RssHttpServer`](struct.RssHttpServer.html), and reloads it from any thread.
+///
+/// A reload reads `http-server.toml` again, with the overrides the server has been created
+/// with. An invalid configuration is rejected and the current one is kept. Otherwise
+/// `shutdown_timeout`, `reload_interval`, `virtual_hosts` and the other
+/// [sections](#method.section) change right away, and every worker builds its service again
+/// through the factory passed to
+/// [`HttpServer::start`](trait.HttpServer.html#tymethod.start): new connections are served by
+/// the new service, open ones keep the old one. Changes to `bind_address`, `bind_port`,
+/// `num_workers` and `handle_signals` are ignored until the server is restarted.
+///
+/// The server reloads its configuration on SIGHUP when `handle_signals` is set, and when
+/// `http-server.toml` is modified if `reload_interval` is not 0 at startup.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    inner: Arc<Shared>,
+}
+
+impl ConfigHandle {
+    pub(crate) fn new(
+        config: RssServerConfig,
+        file: ConfigFile,
+        config_path: PathBuf,
+        missing: MissingConfig,
+        overrides: Vec<ConfigOverrides>,
+    ) -> ConfigHandle {
+        ConfigHandle {
+            inner: Arc::new(Shared {
+                config: RwLock::new(Arc::new(config)),
+                file: RwLock::new(Arc::new(file)),
+                generation: AtomicUsize::new(0),
+                source: Mutex::new(Source {
+                    config_path,
+                    missing,
+                    overrides,
+                }),
+            }),
+        }
+    }
+
+    /// Returns the current configuration.
+    pub(crate) fn current(&self) -> Arc<RssServerConfig> {
+        let config = self.inner
+            .config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(&config)
+    }
+
+    /// Reads the configuration again. On error the current configuration is kept.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let source = self.inner
+            .source
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (mut config, file) =
+            RssServerConfig::load(&source.config_path, source.missing, &source.overrides)?;
+        let current = self.current();
+        let ignored = config.keep_restart_fields(&current);
+        if !ignored.is_empty() {
+            warn!(
+                "changes to {} take effect after a restart",
+                ignored.join(", ")
+            );
+        }
+        if config == *current && file == *self.file() {
+            debug!("configuration unchanged");
+            return Ok(());
+        }
+        *self.inner
+            .config
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+        *self.inner
+            .file
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Arc::new(file);
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+        info!("configuration reloaded");
+        Ok(())
+    }
+
+    /// Reloads the configuration, logging the error if the new one is rejected.
+    pub(crate) fn reload_or_log(&self) {
+        if let Err(e) = self.reload() {
+            error!("new configuration rejected: {}", e);
+        }
+    }
+
+    /// Returns the number of reloads that changed the configuration.
+    pub fn generation(&self) -> usize {
+        self.inner.generation.load(Ordering::SeqCst)
+    }
+
+    /// Returns the current configuration file.
+    fn file(&self) -> Arc<ConfigFile> {
+        let file = self.inner
+            .file
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(&file)
+    }
+
+    /// Deserializes the `[name]` section of the current configuration file, such as `[static]`
+    /// or a section defined by the application, with the overrides applied. See
+    /// [`ConfigFile::section`](struct.ConfigFile.html#method.section).
+    pub fn section<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, ConfigError> {
+        self.file().section(name)
+    }
+
+    /// Returns the directory of `http-server.toml`, which relative paths found in its sections
+    /// are usually relative to.
+    pub fn config_path(&self) -> PathBuf {
+        let source = self.inner
+            .source
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        source.config_path.clone()
+    }
+
+    /// Returns the current `[server.virtual_hosts]` section of the configuration.
+    pub fn virtual_hosts(&self) -> VirtualHostsConfig {
+        self.current().virtual_hosts.clone()
+    }
+
+    /// Returns the current configuration, in the format of `http-server.toml`: the `[server]`
+    /// section in use and the other sections of the file, overrides included.
+    pub fn effective_config(&self) -> String {
+        let mut table = self.file().table("").cloned().unwrap_or_default();
+        let server = Value::try_from(&*self.current()).expect("configuration is serializable");
+        table.insert(String::from("server"), server);
+        toml::to_string(&Value::Table(table)).expect("configuration is serializable")
+    }
+
+    /// Returns the path of `http-server.toml`.
+    fn filename(&self) -> PathBuf {
+        RssServerConfig::filename(&self.config_path())
+    }
+}
+
+/// Background thread checking `http-server.toml` every `reload_interval` seconds, and reloading
+/// the configuration when the file has been modified. The thread exits if `reload_interval` is
+/// reloaded as 0.
+pub(crate) struct ConfigWatcher {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl ConfigWatcher {
+    pub fn new(config: ConfigHandle) -> io::Result<ConfigWatcher> {
+        let (stop, stopped) = channel();
+        let filename = config.filename();
+        let thread = ThreadBuilder::new()
+            .name(String::from("rss-server-config"))
+            .spawn(move || {
+                let mut modified = modified_time(&filename);
+                loop {
+                    let interval = config.current().reload_interval;
+                    if interval == 0 {
+                        info!("stopped watching {}", filename.display());
+                        return;
+                    }
+                    match stopped.recv_timeout(Duration::from_secs(interval)) {
+                        Err(RecvTimeoutError::Timeout) => (),
+                        _ => return,
+                    }
+                    let last_modified = modified_time(&filename);
+                    if last_modified != modified {
+                        modified = last_modified;
+                        info!("{} modified, reloading it", filename.display());
+                        config.reload_or_log();
+                    }
+                }
+            })?;
+        Ok(ConfigWatcher { stop, thread })
+    }
+
+    /// Stops watching and waits for the watcher thread to exit.
+    pub fn stop(self) {
+        drop(self.stop);
+        let _ = self.thread.join();
+    }
+}
+
+/// Returns the modification time of `path`, if it can be read.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{create_dir_all, File};
+    use std::io::Write;
+
+    fn write_config(conf_dir: &Path, content: &str) {
+        File::create(RssServerConfig::filename(conf_dir))
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_reload() {
+        let conf_dir: PathBuf = [
+            env::var("CARGO_MANIFEST_DIR").unwrap().as_str(),
+            "tests",
+            "out",
+            "reload",
+        ].iter()
+            .collect();
+        create_dir_all(&conf_dir).unwrap();
+        write_config(&conf_dir, "bind_port = 8000\nshutdown_timeout = 10\n");
+        let overrides = vec![
+            ConfigOverrides::new()
+                .with("num_workers", "2")
+                .with("static.root", "public"),
+        ];
+        let missing = MissingConfig::Fail;
+        let (config, file) = RssServerConfig::load(&conf_dir, missing, &overrides).unwrap();
+        let handle = ConfigHandle::new(config, file, conf_dir.clone(), missing, overrides);
+
+        // Unchanged file
+        handle.reload().unwrap();
+        assert_eq!(handle.generation(), 0);
+
+        // Reloadable fields are applied, the others kept
+        write_config(
+            &conf_dir,
+            "bind_port = 9000\nshutdown_timeout = 5\n[virtual_hosts]\ndefault = \"main\"\n",
+        );
+        handle.reload().unwrap();
+        assert_eq!(handle.generation(), 1);
+        let current = handle.current();
+        assert_eq!(current.shutdown_timeout, 5);
+        assert_eq!(current.bind_port, 8000);
+        assert_eq!(current.num_workers, 2);
+        assert_eq!(handle.virtual_hosts().default, Some(String::from("main")));
+        let root: Option<String> = handle
+            .section::<toml::value::Table>("static")
+            .unwrap()
+            .and_then(|table| table["root"].as_str().map(String::from));
+        assert_eq!(root, Some(String::from("public")));
+
+        // Invalid configurations are rejected
+        write_config(&conf_dir, "bind_port = 9000\nshutdown_timeout = \"soon\"\n");
+        assert!(handle.reload().is_err());
+        write_config(&conf_dir, "bind_address = \"nowhere\"\n");
+        assert!(handle.reload().is_err());
+        assert_eq!(handle.generation(), 1);
+        assert_eq!(handle.current().shutdown_timeout, 5);
+    }
+}
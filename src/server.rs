@@ -1,117 +1,567 @@
-use futures::prelude::*;
-
-use std::rc::Rc;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
 use hyper::Error as HyperError;
 use hyper::server::{Http, Request as HyperRequest, Response as HyperResponse,
                     Service as HyperService};
 
-use config::RssConfigurable;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+
+use config::{merge_tables, set_value, ConfigError, ConfigFile, ConfigOverrides, FieldError,
+             MissingConfig, OverrideValue, RssConfigurable};
+use pool::{Acceptor, WorkerPool};
+use reload::{ConfigHandle, ConfigWatcher};
+use services::VirtualHostsConfig;
+use shutdown::{ShutdownHandle, SignalWatcher};
 
-use std::fs::File;
+use std::fs::{create_dir_all, File, OpenOptions};
 use std::io::prelude::*;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use toml;
+use toml::Value;
+use toml::value::Table;
 
-pub type ResponseFuture = Box<Future<Item = HyperResponse, Error = HyperError>>;
+/// An `HttpServer` binds a socket and serves HTTP requests until it is shut down.
+pub trait HttpServer {
+    type Err;
 
-pub type RssService = HyperService<
-    Request = HyperRequest,
-    Response = HyperResponse,
-    Error = HyperError,
-    Future = ResponseFuture,
->;
+    /// Starts the server, blocking the current thread until the server is shut down.
+    ///
+    /// `new_service` is called once for every worker thread, with the handle of the worker's
+    /// reactor, and must build the service (usually a [`RouterService`](struct.RouterService.html))
+    /// used by that worker. Since the factory runs inside the worker, the service itself does not
+    /// need to be `Send`.
+    fn start<F, S>(&self, new_service: F) -> Result<(), Self::Err>
+    where
+        F: Fn(&Handle) -> S + Send + Sync + 'static,
+        S: HyperService<Request = HyperRequest, Response = HyperResponse, Error = HyperError>
+            + 'static;
+}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct RssServerConfig {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct RssServerConfig {
     pub bind_address: String,
+    /// Port to listen on, 0 to pick a free one
     pub bind_port: u16,
     pub num_workers: usize,
+    /// Seconds to wait for in-flight requests when shutting down
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Whether SIGTERM and SIGINT trigger a graceful shutdown
+    #[serde(default = "default_handle_signals")]
+    pub handle_signals: bool,
+    /// Seconds between checks of the file for changes, 0 to never check
+    #[serde(default)]
+    pub reload_interval: u64,
+    /// Sites served by host name
+    #[serde(default)]
+    pub virtual_hosts: VirtualHostsConfig,
+}
+
+impl RssServerConfig {
+    /// Returns the path of the `http-server.toml` file of `config_path`.
+    pub(crate) fn filename(config_path: &Path) -> PathBuf {
+        DefaultRssHttpConfigurator::get_conf_filename(config_path)
+    }
+
+    /// Loads the `http-server.toml` file of `config_path`, layering its `[server]` section over
+    /// the defaults and `overrides` over the whole file, and validates the result. When it is
+    /// invalid because of an override, the error is a `ConfigError::Override` naming it. The
+    /// merged file is returned as well, for the other sections.
+    pub(crate) fn load(
+        config_path: &Path,
+        missing: MissingConfig,
+        overrides: &[ConfigOverrides],
+    ) -> Result<(RssServerConfig, ConfigFile), ConfigError> {
+        let filename = RssServerConfig::filename(config_path);
+        let config = DefaultRssHttpConfigurator {
+            path: config_path.to_path_buf(),
+            missing,
+        };
+        let file = config.load_file()?;
+        let defaults = ConfigFile::parse(PathBuf::new(), HTTP_SERVER_CONFIG_STR)
+            .ok()
+            .and_then(|defaults| defaults.table("server").cloned())
+            .expect("default configuration has a [server] section");
+        let mut server = defaults.clone();
+        let mut table = file.table("").cloned().unwrap_or_default();
+        match file.table("server") {
+            Some(section) => merge_tables(&mut server, section.clone()),
+            // Files written before sections existed have the server settings at the top level
+            None => {
+                for field in SERVER_FIELDS {
+                    if let Some(value) = table.remove(*field) {
+                        server.insert((*field).to_owned(), value);
+                    }
+                }
+            }
+        }
+        table.insert(String::from("server"), Value::Table(server));
+
+        // The values that end up in the configuration, by key, to name them in errors
+        let mut applied: Vec<(String, &OverrideValue)> = Vec::new();
+        for layer in overrides {
+            for value in layer.values() {
+                let key = override_key(&value.key);
+                set_value(&mut table, &key, value.value.clone());
+                let nested = format!("{}.", key);
+                applied.retain(|(other, _)| *other != key && !other.starts_with(&nested));
+                applied.push((key, value));
+            }
+        }
+
+        let server_config = match RssServerConfig::from_table(&table) {
+            Ok(server_config) => server_config,
+            Err(e) => {
+                // Values that can't be deserialized fail over the defaults as well
+                for (key, value) in &applied {
+                    let mut checked = Table::new();
+                    checked.insert(String::from("server"), Value::Table(defaults.clone()));
+                    set_value(&mut checked, key, value.value.clone());
+                    if let Err(e) = RssServerConfig::from_table(&checked) {
+                        return Err(ConfigError::Override {
+                            source: value.source.clone(),
+                            message: e.to_string(),
+                        });
+                    }
+                }
+                return Err(ConfigError::parse(filename, &e));
+            }
+        };
+        let errors = server_config.validate();
+        if !errors.is_empty() {
+            for error in &errors {
+                let applied = applied
+                    .iter()
+                    .rev()
+                    .find(|&(key, _)| sets_field(key, &error.field));
+                if let Some(&(_, value)) = applied {
+                    return Err(ConfigError::Override {
+                        source: value.source.clone(),
+                        message: error.to_string(),
+                    });
+                }
+            }
+            return Err(ConfigError::Invalid {
+                path: filename,
+                errors,
+            });
+        }
+        Ok((server_config, file.with_table(table)))
+    }
+
+    /// Deserializes the `[server]` section of `table`.
+    fn from_table(table: &Table) -> Result<RssServerConfig, toml::de::Error> {
+        table
+            .get("server")
+            .cloned()
+            .unwrap_or_else(|| Value::Table(Table::new()))
+            .try_into()
+    }
+
+    /// Restores the fields that can't change while the server is running to their value in
+    /// `current`, returning the names of the ones that differed.
+    pub(crate) fn keep_restart_fields(&mut self, current: &RssServerConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.bind_address != current.bind_address {
+            changed.push("bind_address");
+            self.bind_address = current.bind_address.clone();
+        }
+        if self.bind_port != current.bind_port {
+            changed.push("bind_port");
+            self.bind_port = current.bind_port;
+        }
+        if self.num_workers != current.num_workers {
+            changed.push("num_workers");
+            self.num_workers = current.num_workers;
+        }
+        if self.handle_signals != current.handle_signals {
+            changed.push("handle_signals");
+            self.handle_signals = current.handle_signals;
+        }
+        changed
+    }
+
+    /// Checks the values of the fields, returning the invalid ones.
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.bind_address.parse::<IpAddr>().is_err() {
+            errors.push(FieldError::new(
+                "bind_address",
+                format!("{:?} is not an IP address", self.bind_address),
+            ));
+        }
+        if self.num_workers == 0 {
+            errors.push(FieldError::new("num_workers", "must be greater than zero"));
+        }
+        for (name, hosts) in &self.virtual_hosts.sites {
+            for host in hosts {
+                let name_part = host.strip_prefix("*.").unwrap_or(host);
+                if name_part.is_empty() || name_part.contains('*') {
+                    errors.push(FieldError::new(
+                        format!("virtual_hosts.sites.{}", name),
+                        format!("{:?} is not a host name or a `*.` wildcard", host),
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// Returns `true` if the override `key`, a path in the whole file, sets the `[server]` field
+/// `field`, such as `bind_port` or `virtual_hosts.sites.main`, or a part of it.
+fn sets_field(key: &str, field: &str) -> bool {
+    match key.strip_prefix("server.") {
+        Some(key) => {
+            key == field
+                || field.starts_with(&format!("{}.", key))
+                || key.starts_with(&format!("{}.", field))
+        }
+        None => false,
+    }
+}
+
+/// Fields of the `[server]` section, which overrides can name without the `server.` prefix.
+const SERVER_FIELDS: &[&str] = &[
+    "bind_address",
+    "bind_port",
+    "num_workers",
+    "shutdown_timeout",
+    "handle_signals",
+    "reload_interval",
+    "virtual_hosts",
+];
+
+/// Returns the path in the whole file of the override `key`: `bind_port` and
+/// `virtual_hosts.default` are in `[server]`, `static.root` and `server.bind_port` are kept.
+fn override_key(key: &str) -> String {
+    let field = key.split('.').next().unwrap_or("");
+    if SERVER_FIELDS.contains(&field) {
+        format!("server.{}", key)
+    } else {
+        key.to_owned()
+    }
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_handle_signals() -> bool {
+    true
 }
 
 ///Default implementor of trait [`HttpServer`](trait.HttpServer.html)
 pub struct RssHttpServer {
-    _config: RssServerConfig,
-    _http: Http,
+    /// Configuration the server has been created with
+    config: RssServerConfig,
+    reloadable: ConfigHandle,
+    http: Http,
+    shutdown: ShutdownHandle,
 }
 
 struct DefaultRssHttpConfigurator {
     path: PathBuf,
+    missing: MissingConfig,
 }
 
 /// Server default configuration, converted using serde. This constant is used when no "http-server.toml"
-/// is found in the server `config_path`, this a new toml file with this content is generated.
+/// is found in the server `config_path`, this a new toml file with this content is generated
+/// unless another [`MissingConfig`](enum.MissingConfig.html) behaviour is chosen.
+/// Its `[server]` values are also the defaults of the fields missing from the file.
 pub const HTTP_SERVER_CONFIG_STR: &str = r#"
 # HTTP server configuration
-
+[server]
 bind_address = "127.0.0.1"
 bind_port = 8080
 num_workers = 4
+
+# Seconds to wait for in-flight requests to complete on shutdown
+shutdown_timeout = 30
+# Shut down gracefully on SIGTERM and SIGINT, reload this file on SIGHUP
+handle_signals = true
+# Seconds between checks of this file for changes, 0 to never check
+reload_interval = 0
+
+# Sites served by host name, see VirtualHostService. Wildcards match any subdomain, requests
+# for other hosts are served by the default site.
+# [server.virtual_hosts]
+# default = "main"
+#
+# [server.virtual_hosts.sites]
+# main = ["example.com", "www.example.com"]
+# blog = ["*.blog.example.com"]
+
+# Files served by StaticRouterService::from_config, root is relative to this directory.
+# [static]
+# root = "public"
+# index_files = ["index.html"]
+# autoindex = false
+# autoindex_hidden = false
+
+# Applications can add their own sections, see ConfigHandle::section.
 "#;
 
 impl RssHttpServer {
+    /// Creates a server configured by the `http-server.toml` file of `config_path`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configuration can't be loaded, see [`try_new`](#method.try_new).
     pub fn new(config_path: PathBuf) -> RssHttpServer {
-        let config = DefaultRssHttpConfigurator { path: config_path };
-        let content = config.load().unwrap();
-        let server_config: RssServerConfig = toml::from_str(content.as_str()).unwrap();
-        RssHttpServer {
-            _config: server_config,
+        match RssHttpServer::try_new(config_path) {
+            Ok(server) => server,
+            Err(e) => panic!("{}", e),
         }
     }
+
+    /// Creates a server configured by the `http-server.toml` file of `config_path`, which is
+    /// created with [`HTTP_SERVER_CONFIG_STR`](constant.HTTP_SERVER_CONFIG_STR.html) if missing.
+    /// `config_path` must exist: [`init_config`](#method.init_config) creates it.
+    ///
+    /// Fails if the file can't be read or written, is not valid, or has invalid values: every
+    /// invalid field is reported in [`ConfigError::Invalid`](enum.ConfigError.html).
+    pub fn try_new(config_path: PathBuf) -> Result<RssHttpServer, ConfigError> {
+        RssHttpServer::try_with_overrides(config_path, &[])
+    }
+
+    /// Like [`try_new`](#method.try_new), with `overrides` layered on top of the file.
+    ///
+    /// Values are taken, from the lowest to the highest precedence, from the defaults of
+    /// [`HTTP_SERVER_CONFIG_STR`](constant.HTTP_SERVER_CONFIG_STR.html), the file, then each
+    /// layer of `overrides` in order. Fields missing from the file keep their default.
+    ///
+    /// Keys of the overrides name a section, like `static.root` or `myapp.cache.size`, which
+    /// [`ConfigHandle::section`](struct.ConfigHandle.html#method.section) then returns
+    /// overridden. Fields of the `[server]` section can go without it: `bind_port` stands for
+    /// `server.bind_port`.
+    ///
+    /// Environment variables and command line arguments are read with
+    /// [`ConfigOverrides::from_env`](struct.ConfigOverrides.html#method.from_env) and
+    /// [`ConfigOverrides::from_args`](struct.ConfigOverrides.html#method.from_args), and the
+    /// merged configuration can be dumped with [`effective_config`](#method.effective_config).
+    pub fn try_with_overrides(
+        config_path: PathBuf,
+        overrides: &[ConfigOverrides],
+    ) -> Result<RssHttpServer, ConfigError> {
+        RssHttpServer::try_with_options(config_path, MissingConfig::Create, overrides)
+    }
+
+    /// Like [`try_with_overrides`](#method.try_with_overrides), with `missing` telling what to
+    /// do if `http-server.toml` doesn't exist, at startup or when reloading it.
+    /// [`MissingConfig::Fail`](enum.MissingConfig.html) catches mistyped configuration paths.
+    pub fn try_with_options(
+        config_path: PathBuf,
+        missing: MissingConfig,
+        overrides: &[ConfigOverrides],
+    ) -> Result<RssHttpServer, ConfigError> {
+        let (server_config, file) = RssServerConfig::load(&config_path, missing, overrides)?;
+        let reloadable = ConfigHandle::new(
+            server_config.clone(),
+            file,
+            config_path,
+            missing,
+            overrides.to_vec(),
+        );
+        Ok(RssHttpServer {
+            config: server_config,
+            reloadable,
+            http: Http::new(),
+            shutdown: ShutdownHandle::new(),
+        })
+    }
+
+    /// Writes [`HTTP_SERVER_CONFIG_STR`](constant.HTTP_SERVER_CONFIG_STR.html) to the
+    /// `http-server.toml` file of `config_path`, creating the directory if needed, and returns the
+    /// path of the file. An existing file is only replaced if `overwrite` is set.
+    pub fn init_config(config_path: &Path, overwrite: bool) -> Result<PathBuf, ConfigError> {
+        let path = DefaultRssHttpConfigurator::get_conf_filename(config_path);
+        create_dir_all(config_path).map_err(|source| ConfigError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        write_default_config(&path, overwrite)?;
+        Ok(path)
+    }
+
+    /// Returns the `[server.virtual_hosts]` section of the configuration the server has been
+    /// created with, see [`ConfigHandle`](struct.ConfigHandle.html) for the current one. To be
+    /// passed to
+    /// [`VirtualHostService::from_config`](struct.VirtualHostService.html#method.from_config).
+    pub fn virtual_hosts(&self) -> &VirtualHostsConfig {
+        &self.config.virtual_hosts
+    }
+
+    /// Returns the configuration in use, after defaults, overrides and reloads have been applied,
+    /// in the format of `http-server.toml`.
+    pub fn effective_config(&self) -> String {
+        self.reloadable.effective_config()
+    }
+
+    /// Returns a handle to the configuration, used to reload it and to read its current values.
+    pub fn config_handle(&self) -> ConfigHandle {
+        self.reloadable.clone()
+    }
+
+    /// Returns a handle that can be used, from any thread, to stop the server gracefully.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Returns the socket address built from `bind_address` and `bind_port`.
+    fn socket_addr(&self) -> Result<SocketAddr, HyperError> {
+        let server_address = format!("{}:{}", self.config.bind_address, self.config.bind_port);
+        server_address.parse().map_err(|_| {
+            HyperError::Io(IoError::new(
+                IoErrorKind::InvalidInput,
+                format!("invalid bind address {}", server_address),
+            ))
+        })
+    }
 }
 
 impl DefaultRssHttpConfigurator {
-    pub(crate) fn get_conf_filename(path: &PathBuf) -> PathBuf {
+    pub(crate) fn get_conf_filename(path: &Path) -> PathBuf {
         let mut filename = PathBuf::new();
-        filename.push(path.as_path());
+        filename.push(path);
         filename.push("http-server.toml");
         filename
     }
-    fn save(&self) -> Result<String, HyperError> {
-        let mut file = File::create(Self::get_conf_filename(&self.path)).unwrap();
-
-        file.write_all(HTTP_SERVER_CONFIG_STR.as_bytes())?;
+    /// Writes the default configuration in place of a missing file. The directory is not
+    /// created, and a file created meanwhile is left alone.
+    fn save(&self) -> Result<String, ConfigError> {
+        let path = Self::get_conf_filename(&self.path);
+        write_default_config(&path, false)?;
+        info!("wrote the default configuration to {}", path.display());
         Ok(String::from(HTTP_SERVER_CONFIG_STR))
     }
 }
 
+/// Writes [`HTTP_SERVER_CONFIG_STR`](constant.HTTP_SERVER_CONFIG_STR.html) to `path`. Fails if
+/// the file exists, unless `overwrite` is set.
+fn write_default_config(path: &Path, overwrite: bool) -> Result<(), ConfigError> {
+    OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(overwrite)
+        .create_new(!overwrite)
+        .open(path)
+        .and_then(|mut file| file.write_all(HTTP_SERVER_CONFIG_STR.as_bytes()))
+        .map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
 impl RssConfigurable for DefaultRssHttpConfigurator {
-    fn load(&self) -> Result<String, HyperError> {
-        let file = File::open(Self::get_conf_filename(&self.path));
+    fn load_file(&self) -> Result<ConfigFile, ConfigError> {
+        ConfigFile::parse(Self::get_conf_filename(&self.path), &self.load()?)
+    }
 
-        match file {
+    fn load(&self) -> Result<String, ConfigError> {
+        let path = Self::get_conf_filename(&self.path);
+        match File::open(&path) {
             Ok(mut file) => {
                 let mut contents = String::new();
-                file.read_to_string(&mut contents)?;
-                Ok(contents)
+                match file.read_to_string(&mut contents) {
+                    Ok(_) => Ok(contents),
+                    Err(source) => Err(ConfigError::Io { path, source }),
+                }
             }
-            Err(_) => self.save(),
+            Err(ref e) if e.kind() == IoErrorKind::NotFound => match self.missing {
+                MissingConfig::Create => self.save(),
+                MissingConfig::Defaults => {
+                    info!("{} not found, using the defaults", path.display());
+                    Ok(String::from(HTTP_SERVER_CONFIG_STR))
+                }
+                MissingConfig::Fail => Err(ConfigError::Io {
+                    path,
+                    source: IoError::new(IoErrorKind::NotFound, "configuration file not found"),
+                }),
+            },
+            Err(source) => Err(ConfigError::Io { path, source }),
         }
     }
 }
-//
-// impl HttpServer for RssHttpServer {
-//     type Err = HyperError;
-//     type Item = RssHttpServer;
-//
-//     fn start(&self, service: Rc<RssService>) -> Result<(), Self::Err> {
-//         let server_address = format!("{}:{}", self._config.bind_address, self._config.bind_port);
-//         let addr = server_address.parse().unwrap();
-//         let server = Http::new().bind(&addr, move || Ok(Rc::clone(&service)))?;
-//         server.run()
-//     }
-// }
+
+impl HttpServer for RssHttpServer {
+    type Err = HyperError;
+
+    fn start<F, S>(&self, new_service: F) -> Result<(), Self::Err>
+    where
+        F: Fn(&Handle) -> S + Send + Sync + 'static,
+        S: HyperService<Request = HyperRequest, Response = HyperResponse, Error = HyperError>
+            + 'static,
+    {
+        if self.config.num_workers == 0 {
+            return Err(HyperError::Io(IoError::new(
+                IoErrorKind::InvalidInput,
+                "num_workers must be greater than zero",
+            )));
+        }
+        let addr = self.socket_addr()?;
+        let mut core = Core::new()?;
+        let handle = core.handle();
+        let listener = TcpListener::bind(&addr, &handle)?;
+        self.shutdown.set_local_addr(listener.local_addr()?);
+
+        let signals = if self.config.handle_signals {
+            Some(SignalWatcher::new(
+                self.shutdown_handle(),
+                self.config_handle(),
+            )?)
+        } else {
+            None
+        };
+        let watcher = if self.reloadable.current().reload_interval > 0 {
+            Some(ConfigWatcher::new(self.config_handle())?)
+        } else {
+            None
+        };
+
+        let mut pool = WorkerPool::new(
+            self.config.num_workers,
+            &self.http,
+            Arc::new(new_service),
+            self.config_handle(),
+        )?;
+        let result = core.run(Acceptor::new(
+            listener,
+            &mut pool,
+            self.shutdown.signal(),
+            &handle,
+        ));
+        info!(
+            "stopped accepting connections, waiting up to {:?} for in-flight requests",
+            Duration::from_secs(self.reloadable.current().shutdown_timeout)
+        );
+        pool.join();
+        if let Some(watcher) = watcher {
+            watcher.stop();
+        }
+        if let Some(signals) = signals {
+            signals.stop();
+        }
+        result.map_err(HyperError::from)
+    }
+}
 
 //========================== TESTS =====================================================//
 #[cfg(test)]
 mod tests {
     use super::*;
+    use services::{ETagStrategy, StaticConfig, SymlinkPolicy};
+    use std::collections::BTreeMap;
     use std::env;
+    use toml;
 
     use std::path::PathBuf;
-    use std::fs::remove_file;
+    use std::fs::{create_dir_all, remove_file};
 
     fn get_conf_dir() -> PathBuf {
         [
@@ -125,6 +575,7 @@ mod tests {
     #[test]
     fn writes_and_load_config() {
         let conf_dir = get_conf_dir();
+        create_dir_all(&conf_dir).unwrap();
         let filename = DefaultRssHttpConfigurator::get_conf_filename(&conf_dir.clone());
         if filename.exists() {
             remove_file(filename.clone()).unwrap();
@@ -133,7 +584,7 @@ mod tests {
         let server = RssHttpServer::new(conf_dir);
 
         assert!(filename.exists(), "{:?} does not exist", filename);
-        let config = server._config;
+        let config = server.config;
         let expected = "127.0.0.1";
         assert_eq!(
             config.bind_address,
@@ -158,5 +609,276 @@ mod tests {
             expected,
             config.num_workers
         );
+        assert_eq!(config.virtual_hosts, VirtualHostsConfig::default());
+    }
+
+    #[test]
+    fn parses_virtual_hosts() {
+        let config: RssServerConfig = toml::from_str(
+            r#"
+            bind_address = "0.0.0.0"
+            bind_port = 80
+            num_workers = 2
+
+            [virtual_hosts]
+            default = "main"
+
+            [virtual_hosts.sites]
+            main = ["example.com"]
+            blog = ["*.blog.example.com"]
+            "#,
+        ).unwrap();
+        let virtual_hosts = config.virtual_hosts;
+        assert_eq!(virtual_hosts.default, Some(String::from("main")));
+        assert_eq!(virtual_hosts.sites["main"], vec!["example.com"]);
+        assert_eq!(virtual_hosts.sites["blog"], vec!["*.blog.example.com"]);
+    }
+
+    /// Writes `content` to the `http-server.toml` file of a new config directory.
+    fn write_conf_dir(name: &str, content: &str) -> PathBuf {
+        let conf_dir = get_conf_dir().join(name);
+        create_dir_all(&conf_dir).unwrap();
+        File::create(DefaultRssHttpConfigurator::get_conf_filename(&conf_dir))
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+        conf_dir
+    }
+
+    #[test]
+    fn reads_sections() {
+        let conf_dir = write_conf_dir(
+            "sections",
+            r#"
+            [server]
+            bind_port = 8000
+
+            [static]
+            root = "public"
+            etag = "content_hash"
+
+            [myapp]
+            greeting = "hello"
+            "#,
+        );
+        let server = RssHttpServer::try_with_options(conf_dir.clone(), MissingConfig::Fail, &[])
+            .unwrap();
+        assert_eq!(server.config.bind_port, 8000);
+        assert_eq!(server.config.num_workers, 4);
+
+        let config = server.config_handle();
+        assert_eq!(config.config_path(), conf_dir);
+        let static_config: StaticConfig = config.section("static").unwrap().unwrap();
+        assert_eq!(static_config.root, PathBuf::from("public"));
+        assert_eq!(static_config.etag, ETagStrategy::ContentHash);
+        assert_eq!(static_config.symlinks, SymlinkPolicy::FollowWithinRoot);
+        assert!(static_config.precompressed);
+        let greeting: Option<BTreeMap<String, String>> = config.section("myapp").unwrap();
+        assert_eq!(greeting.unwrap()["greeting"], "hello");
+        assert!(config.section::<StaticConfig>("tls").unwrap().is_none());
+    }
+
+    #[test]
+    fn layers_overrides() {
+        let conf_dir = write_conf_dir(
+            "overrides",
+            r#"
+            bind_address = "0.0.0.0"
+            bind_port = 80
+
+            [virtual_hosts]
+            default = "main"
+
+            [myapp]
+            greeting = "hello"
+            "#,
+        );
+        let env = ConfigOverrides::from_vars(
+            "RSS_",
+            vec![
+                (String::from("RSS_BIND_PORT"), String::from("8000")),
+                (String::from("RSS_NUM_WORKERS"), String::from("2")),
+                (String::from("RSS_VIRTUAL_HOSTS__DEFAULT"), String::from("blog")),
+                (String::from("RSS_STATIC__ROOT"), String::from("public")),
+                (String::from("HOME"), String::from("/root")),
+            ],
+        );
+        let args = ConfigOverrides::from_args(
+            vec![
+                "--bind-port=9000",
+                "--virtual-hosts.sites.blog",
+                "[\"*.blog.com\"]",
+                "--static.index-files=[\"home.html\"]",
+                "--server.shutdown-timeout=10",
+            ].into_iter()
+                .map(String::from),
+        ).unwrap();
+        let server = RssHttpServer::try_with_overrides(conf_dir, &[env, args]).unwrap();
+
+        let config = &server.config;
+        assert_eq!(config.bind_address, "0.0.0.0");
+        assert_eq!(config.bind_port, 9000);
+        assert_eq!(config.num_workers, 2);
+        assert_eq!(config.shutdown_timeout, 10);
+        assert_eq!(config.virtual_hosts.default, Some(String::from("blog")));
+        assert_eq!(config.virtual_hosts.sites["blog"], vec!["*.blog.com"]);
+
+        // Sections are overridden too
+        let handle = server.config_handle();
+        let static_config: StaticConfig = handle.section("static").unwrap().unwrap();
+        assert_eq!(static_config.root, PathBuf::from("public"));
+        assert_eq!(static_config.index_files, vec!["home.html"]);
+        let myapp: BTreeMap<String, String> = handle.section("myapp").unwrap().unwrap();
+        assert_eq!(myapp["greeting"], "hello");
+
+        let effective_config = server.effective_config();
+        assert!(effective_config.contains("[server]"), "{}", effective_config);
+        let dumped = ConfigFile::parse(PathBuf::new(), &effective_config).unwrap();
+        let dumped_server: RssServerConfig = dumped.section("server").unwrap().unwrap();
+        assert_eq!(dumped_server, *config);
+        let dumped_static: StaticConfig = dumped.section("static").unwrap().unwrap();
+        assert_eq!(dumped_static.root, PathBuf::from("public"));
+        let dumped_myapp: BTreeMap<String, String> = dumped.section("myapp").unwrap().unwrap();
+        assert_eq!(dumped_myapp, myapp);
+
+        // Invalid overrides are named in errors
+        let conf_dir = write_conf_dir("invalid_overrides", "bind_port = 80\n");
+        let env = ConfigOverrides::from_vars(
+            "RSS_",
+            vec![(String::from("RSS_BIND_PORT"), String::from("abc"))],
+        );
+        let args = ConfigOverrides::from_args(vec![String::from("--bind-port=-1")]).unwrap();
+        for (layer, expected) in [(env, "RSS_BIND_PORT"), (args, "--bind-port")] {
+            match RssHttpServer::try_with_overrides(conf_dir.clone(), &[layer]) {
+                Err(ConfigError::Override { ref source, .. }) => assert_eq!(source, expected),
+                other => panic!("unexpected result {:?}", other.err()),
+            }
+        }
+        let env = ConfigOverrides::from_vars(
+            "RSS_",
+            vec![(String::from("RSS_NUM_WORKERS"), String::from("0"))],
+        );
+        let error = RssHttpServer::try_with_overrides(conf_dir.clone(), &[env]).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "RSS_NUM_WORKERS: num_workers: must be greater than zero"
+        );
+        // Only the final value of a key is validated
+        let env = ConfigOverrides::from_vars(
+            "RSS_",
+            vec![(String::from("RSS_NUM_WORKERS"), String::from("0"))],
+        );
+        let args = ConfigOverrides::from_args(vec![String::from("--num-workers=2")]).unwrap();
+        let server = RssHttpServer::try_with_overrides(conf_dir, &[env, args]).unwrap();
+        assert_eq!(server.config.num_workers, 2);
+
+        let error = ConfigOverrides::from_args(vec![String::from("--bind-port")]).unwrap_err();
+        assert_eq!(error.to_string(), "--bind-port: missing value");
+        assert!(ConfigOverrides::from_args(vec![String::from("8080")]).is_err());
+    }
+
+    #[test]
+    fn handles_missing_config() {
+        let conf_dir = get_conf_dir().join("missing_config");
+        let filename = DefaultRssHttpConfigurator::get_conf_filename(&conf_dir);
+        if filename.exists() {
+            remove_file(&filename).unwrap();
+        }
+
+        match RssHttpServer::try_with_options(conf_dir.clone(), MissingConfig::Fail, &[]) {
+            Err(ConfigError::Io { path, source }) => {
+                assert_eq!(path, filename);
+                assert_eq!(source.kind(), IoErrorKind::NotFound);
+            }
+            other => panic!("unexpected result {:?}", other.err()),
+        }
+        let defaults = MissingConfig::Defaults;
+        let server = RssHttpServer::try_with_options(conf_dir.clone(), defaults, &[]).unwrap();
+        assert_eq!(server.config.bind_port, 8080);
+        assert!(!filename.exists());
+
+        assert_eq!(RssHttpServer::init_config(&conf_dir, false).unwrap(), filename);
+        match RssHttpServer::init_config(&conf_dir, false) {
+            Err(ConfigError::Io { ref source, .. }) => {
+                assert_eq!(source.kind(), IoErrorKind::AlreadyExists)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let missing_dir = conf_dir.join("missing");
+        match RssHttpServer::try_with_options(missing_dir.clone(), MissingConfig::Create, &[]) {
+            Err(ConfigError::Io { ref source, .. }) => {
+                assert_eq!(source.kind(), IoErrorKind::NotFound)
+            }
+            other => panic!("unexpected result {:?}", other.err()),
+        }
+        assert!(!missing_dir.exists());
+        write_conf_dir("missing_config", "bind_port = 80\n");
+        RssHttpServer::init_config(&conf_dir, true).unwrap();
+        let server = RssHttpServer::try_with_options(conf_dir, MissingConfig::Fail, &[]).unwrap();
+        assert_eq!(server.config.bind_port, 8080);
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let conf_dir = write_conf_dir(
+            "parse_error",
+            "bind_address = \"127.0.0.1\"\nbind_port = = 80\nnum_workers = 4\n",
+        );
+        match RssHttpServer::try_new(conf_dir.clone()) {
+            Err(ConfigError::Parse {
+                path,
+                line,
+                column,
+                ..
+            }) => {
+                assert_eq!(path, conf_dir.join("http-server.toml"));
+                assert_eq!((line, column), (Some(2), Some(13)));
+            }
+            other => panic!("unexpected result {:?}", other.err()),
+        }
+
+        // Type errors are reported without position, but name the field
+        let conf_dir = write_conf_dir(
+            "type_error",
+            "bind_address = \"127.0.0.1\"\nbind_port = \"http\"\nnum_workers = 4\n",
+        );
+        let error = RssHttpServer::try_new(conf_dir).err().unwrap();
+        assert!(error.to_string().contains("bind_port"), "{}", error);
+    }
+
+    #[test]
+    fn reports_invalid_fields() {
+        let conf_dir = write_conf_dir(
+            "invalid_fields",
+            r#"
+            bind_address = "localhost:80"
+            bind_port = 80
+            num_workers = 0
+
+            [virtual_hosts.sites]
+            main = ["example.com", "*.example.com", "www.*.com", ""]
+            "#,
+        );
+        let error = RssHttpServer::try_new(conf_dir).err().unwrap();
+        match error {
+            ConfigError::Invalid { ref errors, .. } => {
+                let fields: Vec<&str> = errors.iter().map(|error| error.field.as_str()).collect();
+                assert_eq!(
+                    fields,
+                    vec![
+                        "bind_address",
+                        "num_workers",
+                        "virtual_hosts.sites.main",
+                        "virtual_hosts.sites.main",
+                    ]
+                );
+            }
+            ref other => panic!("unexpected error {}", other),
+        }
+        assert!(
+            error.to_string().contains("\n  num_workers: must be greater than zero"),
+            "{}",
+            error
+        );
     }
 }
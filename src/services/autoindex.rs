@@ -0,0 +1,370 @@
+use hyper::header::{Accept, HttpDate};
+use hyper::mime;
+use hyper::server::Request as HyperRequest;
+
+use std::cmp::Ordering;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An entry of a directory listing.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ListingEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+impl ListingEntry {
+    fn new(name: String, metadata: &Metadata) -> ListingEntry {
+        ListingEntry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        }
+    }
+
+    fn mtime(&self) -> Option<u64> {
+        self.modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+    }
+}
+
+/// The key a listing is sorted by, chosen with the `sort` query parameter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+/// The representation of a listing, chosen with the `format` query parameter or, when it is
+/// missing, with the `Accept` header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ListingFormat {
+    Html,
+    Json,
+}
+
+/// How a listing has been requested: `?sort=name|size|mtime&order=asc|desc&format=html|json`.
+///
+/// Unknown parameters and values are ignored.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ListingQuery {
+    pub sort: SortKey,
+    pub descending: bool,
+    pub format: ListingFormat,
+}
+
+impl ListingQuery {
+    pub fn from_request(req: &HyperRequest) -> ListingQuery {
+        let mut query = ListingQuery {
+            sort: SortKey::Name,
+            descending: false,
+            format: if accepts_json(req) {
+                ListingFormat::Json
+            } else {
+                ListingFormat::Html
+            },
+        };
+        for pair in req.query().unwrap_or("").split('&') {
+            let mut pair = pair.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some("sort"), Some("name")) => query.sort = SortKey::Name,
+                (Some("sort"), Some("size")) => query.sort = SortKey::Size,
+                (Some("sort"), Some("mtime")) => query.sort = SortKey::Modified,
+                (Some("order"), Some("asc")) => query.descending = false,
+                (Some("order"), Some("desc")) => query.descending = true,
+                (Some("format"), Some("html")) => query.format = ListingFormat::Html,
+                (Some("format"), Some("json")) => query.format = ListingFormat::Json,
+                _ => (),
+            }
+        }
+        query
+    }
+}
+
+/// Returns `true` if the client prefers JSON over HTML.
+fn accepts_json(req: &HyperRequest) -> bool {
+    let accept = match req.headers().get::<Accept>() {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let quality = |wanted: &mime::Mime| {
+        accept
+            .iter()
+            .filter(|item| item.item == *wanted)
+            .map(|item| item.quality)
+            .max()
+    };
+    match (quality(&mime::APPLICATION_JSON), quality(&mime::TEXT_HTML)) {
+        (Some(json), Some(html)) => json > html,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+/// Reads the entries of `dir`, skipping the ones `include` rejects and the ones whose metadata
+/// can't be read, and sorts them as requested. Directories always come first. Hidden entries,
+/// whose name starts with a dot, are skipped unless `hidden` is set.
+pub(crate) fn read_listing<F>(
+    dir: &Path,
+    query: &ListingQuery,
+    hidden: bool,
+    include: F,
+) -> io::Result<Vec<ListingEntry>>
+where
+    F: Fn(&Path) -> bool,
+{
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !include(&path) {
+            continue;
+        }
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name.starts_with('.') && !hidden {
+            continue;
+        }
+        match path.metadata() {
+            Ok(metadata) => entries.push(ListingEntry::new(name, &metadata)),
+            Err(e) => debug!("skipping {:?} from listing: {}", path, e),
+        }
+    }
+    entries.sort_by(|a, b| {
+        let ordering = match query.sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }.then_with(|| a.name.cmp(&b.name));
+        let ordering = if query.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+    Ok(entries)
+}
+
+/// Renders a listing of the directory found at `req_path`.
+pub(crate) fn render_listing(
+    req_path: &str,
+    entries: &[ListingEntry],
+    format: ListingFormat,
+) -> String {
+    match format {
+        ListingFormat::Html => render_html(req_path, entries),
+        ListingFormat::Json => render_json(req_path, entries),
+    }
+}
+
+fn render_html(req_path: &str, entries: &[ListingEntry]) -> String {
+    let title = format!("Index of {}", escape_html(req_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+         <body>\n<h1>{0}</h1>\n<table>\n<tr>\
+         <th><a href=\"?sort=name\">Name</a></th>\
+         <th><a href=\"?sort=size\">Size</a></th>\
+         <th><a href=\"?sort=mtime\">Last modified</a></th></tr>\n",
+        title
+    );
+    if req_path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let name = if entry.is_dir {
+            format!("{}/", entry.name)
+        } else {
+            entry.name.clone()
+        };
+        let size = if entry.is_dir {
+            String::from("-")
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry
+            .modified
+            .map(|modified| HttpDate::from(modified).to_string())
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&encode_href(&name)),
+            escape_html(&name),
+            size,
+            modified
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn render_json(req_path: &str, entries: &[ListingEntry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"name\":{},\"type\":\"{}\",\"size\":{},\"mtime\":{}}}",
+                escape_json(&entry.name),
+                if entry.is_dir { "directory" } else { "file" },
+                entry.size,
+                entry
+                    .mtime()
+                    .map_or_else(|| String::from("null"), |mtime| mtime.to_string())
+            )
+        })
+        .collect();
+    format!(
+        "{{\"path\":{},\"entries\":[{}]}}",
+        escape_json(req_path),
+        entries.join(",")
+    )
+}
+
+/// Percent-encodes the characters of a file name that would break a relative link.
+fn encode_href(name: &str) -> String {
+    let mut href = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                href.push(byte as char)
+            }
+            _ => href.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    href
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+    use hyper::header::qitem;
+    use std::time::Duration;
+
+    fn get_request(uri: &str) -> HyperRequest {
+        let uri = format!("https://www.rss-server.org{}", uri).parse().unwrap();
+        HyperRequest::new(Method::Get, uri)
+    }
+
+    fn get_entries() -> Vec<ListingEntry> {
+        vec![
+            ListingEntry {
+                name: String::from("b.txt"),
+                is_dir: false,
+                size: 10,
+                modified: Some(UNIX_EPOCH + Duration::from_secs(300)),
+            },
+            ListingEntry {
+                name: String::from("a <\"x\">.txt"),
+                is_dir: false,
+                size: 20,
+                modified: Some(UNIX_EPOCH + Duration::from_secs(100)),
+            },
+            ListingEntry {
+                name: String::from("dir"),
+                is_dir: true,
+                size: 0,
+                modified: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_listing_query() {
+        let query = ListingQuery::from_request(&get_request("/dir/"));
+        assert_eq!(
+            query,
+            ListingQuery {
+                sort: SortKey::Name,
+                descending: false,
+                format: ListingFormat::Html,
+            }
+        );
+
+        let query =
+            ListingQuery::from_request(&get_request("/dir/?sort=mtime&order=desc&format=json&x"));
+        assert_eq!(
+            query,
+            ListingQuery {
+                sort: SortKey::Modified,
+                descending: true,
+                format: ListingFormat::Json,
+            }
+        );
+
+        let mut req = get_request("/dir/?sort=bogus");
+        req.headers_mut()
+            .set(Accept(vec![qitem(mime::APPLICATION_JSON)]));
+        let query = ListingQuery::from_request(&req);
+        assert_eq!(query.sort, SortKey::Name);
+        assert_eq!(query.format, ListingFormat::Json);
+    }
+
+    #[test]
+    fn test_render_json() {
+        let json = render_listing("/a\"b/", &get_entries()[1..], ListingFormat::Json);
+        assert_eq!(
+            json,
+            "{\"path\":\"/a\\\"b/\",\"entries\":[\
+             {\"name\":\"a <\\\"x\\\">.txt\",\"type\":\"file\",\"size\":20,\"mtime\":100},\
+             {\"name\":\"dir\",\"type\":\"directory\",\"size\":0,\"mtime\":null}]}"
+        );
+    }
+
+    #[test]
+    fn test_render_html() {
+        let html = render_listing("/<dir>/", &get_entries(), ListingFormat::Html);
+        assert!(html.contains("<title>Index of /&lt;dir&gt;/</title>"));
+        assert!(html.contains("<a href=\"../\">../</a>"));
+        assert!(html.contains(
+            "<a href=\"a%20%3C%22x%22%3E.txt\">a &lt;&quot;x&quot;&gt;.txt</a></td><td>20</td>"
+        ));
+        assert!(html.contains("<a href=\"dir/\">dir/</a></td><td>-</td><td></td>"));
+
+        let html = render_listing("/", &[], ListingFormat::Html);
+        assert!(!html.contains("../"));
+    }
+}
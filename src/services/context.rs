@@ -0,0 +1,122 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// A `RouteContext` holds per-request values, one per type, shared by the
+/// [`Router`](trait.Router.html) that routes a request, its `dispatch` and the
+/// [`ErrorHandler`](trait.ErrorHandler.html).
+///
+/// [`RouterService`](struct.RouterService.html) creates a context for every request and passes
+/// it to [`Router::route`](trait.Router.html#tymethod.route) and
+/// [`Router::dispatch`](trait.Router.html#tymethod.dispatch). Errors reaching the error handler
+/// carry it in [`HttpError::context`](struct.HttpError.html#structfield.context). Values stored
+/// while routing (matched params, looked up entities, the authenticated principal, ...) don't
+/// need to be computed again when dispatching.
+///
+/// Cloning a context is cheap and the clone shares its values, so a context can be moved into
+/// the futures returned by `route` and `dispatch`.
+///
+/// Since every router of a `RouterService` is tried with the same context, routers should store
+/// values only when they accept the request.
+#[derive(Clone, Default)]
+pub struct RouteContext {
+    values: Rc<RefCell<HashMap<TypeId, Box<dyn Any>>>>,
+}
+
+impl RouteContext {
+    /// Creates an empty context.
+    pub fn new() -> RouteContext {
+        RouteContext::default()
+    }
+
+    /// Stores `value`, returning the value of the same type stored before, if any.
+    pub fn insert<T: Any>(&self, value: T) -> Option<T> {
+        self.values
+            .borrow_mut()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// Returns a copy of the value of type `T`. Values that are expensive or impossible to clone
+    /// can be stored behind an `Rc`.
+    pub fn get<T: Any + Clone>(&self) -> Option<T> {
+        self.with(|value: &T| value.clone())
+    }
+
+    /// Calls `f` with a reference to the value of type `T`, if any.
+    ///
+    /// The context can't be modified from `f`.
+    pub fn with<T: Any, R, F: FnOnce(&T) -> R>(&self, f: F) -> Option<R> {
+        self.values
+            .borrow()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+            .map(f)
+    }
+
+    /// Removes the value of type `T` from the context and returns it.
+    pub fn remove<T: Any>(&self) -> Option<T> {
+        self.values
+            .borrow_mut()
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Returns `true` if the context holds a value of type `T`.
+    pub fn contains<T: Any>(&self) -> bool {
+        self.values.borrow().contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl fmt::Debug for RouteContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RouteContext {{ {} values }}", self.values.borrow().len())
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User(String);
+
+    #[test]
+    fn test_insert_get_remove() {
+        let context = RouteContext::new();
+        assert_eq!(context.get::<User>(), None);
+        assert!(!context.contains::<User>());
+
+        assert_eq!(context.insert(User(String::from("alice"))), None);
+        assert_eq!(context.insert(42u32), None);
+        assert_eq!(
+            context.insert(User(String::from("bob"))),
+            Some(User(String::from("alice")))
+        );
+        assert_eq!(context.get::<User>(), Some(User(String::from("bob"))));
+        assert_eq!(context.with(|user: &User| user.0.len()), Some(3));
+        assert_eq!(context.get::<u32>(), Some(42));
+        assert_eq!(context.get::<u64>(), None);
+
+        assert_eq!(context.remove::<u32>(), Some(42));
+        assert_eq!(context.remove::<u32>(), None);
+        assert!(context.contains::<User>());
+    }
+
+    #[test]
+    fn test_clones_share_values() {
+        let context = RouteContext::new();
+        let clone = context.clone();
+        clone.insert(Rc::new(User(String::from("carol"))));
+        assert_eq!(
+            context.get::<Rc<User>>(),
+            Some(Rc::new(User(String::from("carol"))))
+        );
+        assert_eq!(format!("{:?}", context), "RouteContext { 1 values }");
+    }
+}
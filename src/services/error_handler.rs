@@ -0,0 +1,440 @@
+use HttpError;
+use services::{ErrorHandler, Negotiator, ResponseFuture};
+use services::autoindex::{escape_html, escape_json};
+
+use futures::future::ok;
+use hyper::header::{ContentLength, ContentType};
+use hyper::mime;
+use hyper::server::Response as HyperResponse;
+
+use std::collections::HashMap;
+use std::fs::{read_dir, File};
+use std::io::Read;
+use std::path::Path;
+
+/// The format of an error page.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum ErrorFormat {
+    Html,
+    Json,
+    Text,
+}
+
+impl ErrorFormat {
+    fn from_extension(extension: &str) -> Option<ErrorFormat> {
+        match extension {
+            "html" => Some(ErrorFormat::Html),
+            "json" => Some(ErrorFormat::Json),
+            "txt" => Some(ErrorFormat::Text),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> ContentType {
+        match self {
+            ErrorFormat::Html => ContentType::html(),
+            ErrorFormat::Json => ContentType("application/problem+json".parse().unwrap()),
+            ErrorFormat::Text => ContentType::plaintext(),
+        }
+    }
+
+    fn escape(self, text: &str) -> String {
+        match self {
+            ErrorFormat::Html => escape_html(text),
+            ErrorFormat::Json => escape_json(text),
+            ErrorFormat::Text => text.to_owned(),
+        }
+    }
+}
+
+/// The values an error page is rendered with.
+struct ErrorPage {
+    status: u16,
+    reason: String,
+    description: String,
+    message: Option<String>,
+    details: Vec<(String, String)>,
+    method: String,
+    path: String,
+}
+
+impl ErrorPage {
+    fn new(http_error: &HttpError) -> ErrorPage {
+        ErrorPage {
+            status: http_error.status_code.as_u16(),
+            reason: http_error
+                .status_code
+                .canonical_reason()
+                .unwrap_or("")
+                .to_owned(),
+            description: http_error.description().to_owned(),
+            message: http_error.message().map(String::from),
+            details: http_error.details().to_vec(),
+            method: http_error.request.method().to_string(),
+            path: http_error.request.path().to_owned(),
+        }
+    }
+
+    /// Replaces the placeholders of `template` with the escaped values of the page. The template
+    /// is scanned once, so placeholders found in the values are left as they are.
+    fn fill(&self, template: &str, format: ErrorFormat) -> String {
+        let mut filled = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            filled.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = match rest.find("}}") {
+                Some(end) => end,
+                None => break,
+            };
+            match self.placeholder(&rest[2..end], format) {
+                Some(value) => {
+                    filled.push_str(&value);
+                    rest = &rest[end + 2..];
+                }
+                None => {
+                    filled.push_str("{{");
+                    rest = &rest[2..];
+                }
+            }
+        }
+        filled.push_str(rest);
+        filled
+    }
+
+    /// Returns the escaped value of the placeholder `name`, if it is one.
+    fn placeholder(&self, name: &str, format: ErrorFormat) -> Option<String> {
+        match name {
+            "status" => Some(self.status.to_string()),
+            "reason" => Some(format.escape(&self.reason)),
+            "description" => Some(format.escape(&self.description)),
+            "message" => Some(format.escape(self.message.as_ref().unwrap_or(&self.description))),
+            "method" => Some(format.escape(&self.method)),
+            "path" => Some(format.escape(&self.path)),
+            _ => None,
+        }
+    }
+
+    fn render(&self, format: ErrorFormat) -> String {
+        match format {
+            ErrorFormat::Html => {
+                let mut content = String::new();
+                if let Some(ref message) = self.message {
+                    content.push_str(&format!("<p>{}</p>\n", escape_html(message)));
+                }
+                if !self.details.is_empty() {
+                    content.push_str("<dl>\n");
+                    for (name, value) in &self.details {
+                        content.push_str(&format!(
+                            "<dt>{}</dt><dd>{}</dd>\n",
+                            escape_html(name),
+                            escape_html(value)
+                        ));
+                    }
+                    content.push_str("</dl>\n");
+                }
+                format!(
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                     <title>{status} {reason}</title>\n</head>\n<body>\n\
+                     <h1>{status} {reason}</h1>\n{content}</body>\n</html>\n",
+                    status = self.status,
+                    reason = escape_html(&self.reason),
+                    content = content
+                )
+            }
+            ErrorFormat::Json => {
+                let mut members = vec![
+                    String::from("\"type\":\"about:blank\""),
+                    format!("\"title\":{}", escape_json(&self.reason)),
+                    format!("\"status\":{}", self.status),
+                ];
+                if let Some(ref message) = self.message {
+                    members.push(format!("\"detail\":{}", escape_json(message)));
+                }
+                members.push(format!("\"instance\":{}", escape_json(&self.path)));
+                if !self.details.is_empty() {
+                    let details: Vec<String> = self.details
+                        .iter()
+                        .map(|(name, value)| {
+                            format!("{}:{}", escape_json(name), escape_json(value))
+                        })
+                        .collect();
+                    members.push(format!("\"details\":{{{}}}", details.join(",")));
+                }
+                format!("{{{}}}", members.join(","))
+            }
+            ErrorFormat::Text => {
+                let mut text = format!("{} {}\n", self.status, self.reason);
+                if let Some(ref message) = self.message {
+                    text.push_str(message);
+                    text.push('\n');
+                }
+                for (name, value) in &self.details {
+                    text.push_str(&format!("{}: {}\n", name, value));
+                }
+                text
+            }
+        }
+    }
+}
+
+/// A `DefaultErrorHandler` is an [`ErrorHandler`](trait.ErrorHandler.html) rendering error pages
+/// from the status code, the message and the details of the [`HttpError`](struct.HttpError.html).
+/// The headers of the error are sent along with the page. The source of the error is never
+/// shown to the client.
+///
+/// The format of the page is negotiated with the `Accept` header of the request: HTML by
+/// default, JSON following RFC 7807 (`application/problem+json`) or plain text. When the client
+/// accepts none of them, plain text is sent.
+///
+/// Pages can be customized, per status code and format, with [templates](#method.with_templates)
+/// whose `{{status}}`, `{{reason}}`, `{{description}}`, `{{message}}` (the description when the
+/// error has no message), `{{method}}` and `{{path}}` placeholders are replaced with the values
+/// of the error, escaped for the format: in JSON templates, the placeholders other than
+/// `{{status}}` are replaced with JSON strings, quotes included.
+pub struct DefaultErrorHandler {
+    templates: HashMap<(u16, ErrorFormat), String>,
+    negotiator: Negotiator,
+}
+
+impl Default for DefaultErrorHandler {
+    fn default() -> DefaultErrorHandler {
+        DefaultErrorHandler {
+            templates: HashMap::new(),
+            negotiator: Negotiator::new().with_media_types(vec![
+                mime::TEXT_HTML,
+                "application/problem+json".parse().unwrap(),
+                mime::APPLICATION_JSON,
+                mime::TEXT_PLAIN,
+            ]),
+        }
+    }
+}
+
+impl DefaultErrorHandler {
+    /// Creates a handler rendering the built-in pages.
+    pub fn new() -> DefaultErrorHandler {
+        DefaultErrorHandler::default()
+    }
+
+    /// Loads the templates found in the `errors` directory of `config_path`, the directory
+    /// holding `http-server.toml`.
+    ///
+    /// Templates are named after the status code and the format they render, like `404.html`,
+    /// `404.json` or `500.txt`. Other files are ignored, and so are templates that can't be read,
+    /// with a warning. Templates are read once, when the handler is created.
+    pub fn with_templates<P: AsRef<Path>>(mut self, config_path: P) -> DefaultErrorHandler {
+        let dir = config_path.as_ref().join("errors");
+        let entries = match read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("error templates not loaded from {:?}: {}", dir, e);
+                return self;
+            }
+        };
+        for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            let status = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u16>().ok());
+            let format = path.extension()
+                .and_then(|extension| extension.to_str())
+                .and_then(ErrorFormat::from_extension);
+            if let (Some(status), Some(format)) = (status, format) {
+                let mut template = String::new();
+                match File::open(&path).and_then(|mut file| file.read_to_string(&mut template)) {
+                    Ok(_) => {
+                        self.templates.insert((status, format), template);
+                    }
+                    Err(e) => warn!("error template {:?} not loaded: {}", path, e),
+                }
+            }
+        }
+        self
+    }
+
+    /// Returns the format of the page for `http_error`.
+    fn format(&self, http_error: &HttpError) -> ErrorFormat {
+        let negotiated = self.negotiator
+            .select(&http_error.request)
+            .and_then(|negotiated| negotiated.media_type);
+        match negotiated {
+            Some(ref media_type) if *media_type == mime::TEXT_HTML => ErrorFormat::Html,
+            Some(ref media_type) if *media_type == mime::TEXT_PLAIN => ErrorFormat::Text,
+            Some(_) => ErrorFormat::Json,
+            None => ErrorFormat::Text,
+        }
+    }
+}
+
+impl ErrorHandler for DefaultErrorHandler {
+    fn dispatch(&self, http_error: HttpError) -> ResponseFuture {
+        let format = self.format(&http_error);
+        let page = ErrorPage::new(&http_error);
+        let content = match self.templates.get(&(page.status, format)) {
+            Some(template) => page.fill(template, format),
+            None => page.render(format),
+        };
+        let mut res = HyperResponse::new()
+            .with_status(http_error.status_code)
+            .with_header(format.content_type())
+            .with_header(ContentLength(content.len() as u64))
+            .with_body(content);
+        res.headers_mut().extend(http_error.headers.iter());
+        self.negotiator.set_vary(&mut res);
+        Box::new(ok(res))
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+    use hyper::{Method, StatusCode};
+    use hyper::header::RetryAfter;
+    use hyper::server::Request as HyperRequest;
+    use std::env;
+    use std::fs::create_dir_all;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::str::from_utf8;
+    use std::time::Duration;
+
+    fn get_error(path: &str, accept: Option<&'static str>, status_code: StatusCode) -> HttpError {
+        let uri = format!("https://www.rss-server.org{}", path).parse().unwrap();
+        let mut req = HyperRequest::new(Method::Get, uri);
+        if let Some(accept) = accept {
+            req.headers_mut().set_raw("Accept", accept);
+        }
+        HttpError::new(req, status_code)
+    }
+
+    fn render(handler: &DefaultErrorHandler, http_error: HttpError) -> (String, String) {
+        let response = handler.dispatch(http_error).wait().unwrap();
+        let content_type = response.headers().get::<ContentType>().unwrap().to_string();
+        let body = response.body().concat2().wait().unwrap();
+        (content_type, from_utf8(&body).unwrap().to_owned())
+    }
+
+    #[test]
+    fn test_formats() {
+        let handler = DefaultErrorHandler::new();
+
+        let response = handler
+            .dispatch(get_error("/missing", None, StatusCode::NotFound))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NotFound);
+        assert_eq!(response.headers().get_raw("Vary").unwrap(), "Accept");
+
+        let (content_type, body) =
+            render(&handler, get_error("/a<b>", None, StatusCode::NotFound));
+        assert_eq!(content_type, "text/html");
+        assert!(body.contains("<title>404 Not Found</title>"), "{}", body);
+        assert!(body.contains("<h1>404 Not Found</h1>"), "{}", body);
+
+        let accept = Some("application/json");
+        let (content_type, body) =
+            render(&handler, get_error("/a\"b", accept, StatusCode::NotFound));
+        assert_eq!(content_type, "application/problem+json");
+        assert_eq!(
+            body,
+            r#"{"type":"about:blank","title":"Not Found","status":404,"instance":"/a\"b"}"#
+        );
+
+        let accept = Some("text/plain, text/html;q=0.5");
+        assert_eq!(
+            render(&handler, get_error("/", accept, StatusCode::Forbidden)),
+            (String::from("text/plain; charset=utf-8"), String::from("403 Forbidden\n"))
+        );
+        // Nothing acceptable
+        assert_eq!(
+            render(&handler, get_error("/", Some("image/png"), StatusCode::Gone)),
+            (String::from("text/plain; charset=utf-8"), String::from("410 Gone\n"))
+        );
+    }
+
+    #[test]
+    fn test_message_details_and_headers() {
+        let handler = DefaultErrorHandler::new();
+        let error = || {
+            get_error("/login", Some("application/json"), StatusCode::Unauthorized)
+                .with_message("Invalid <credentials>")
+                .with_detail("field", "password")
+                .with_header(RetryAfter::Delay(Duration::from_secs(30)))
+        };
+
+        let response = handler.dispatch(error()).wait().unwrap();
+        assert_eq!(response.headers().get_raw("Retry-After").unwrap(), "30");
+        let (_, body) = render(&handler, error());
+        assert_eq!(
+            body,
+            "{\"type\":\"about:blank\",\"title\":\"Unauthorized\",\"status\":401,\
+             \"detail\":\"Invalid <credentials>\",\"instance\":\"/login\",\
+             \"details\":{\"field\":\"password\"}}"
+        );
+
+        let mut http_error = error();
+        http_error.request.headers_mut().set_raw("Accept", "text/html");
+        let (_, body) = render(&handler, http_error);
+        assert!(body.contains("<p>Invalid &lt;credentials&gt;</p>"), "{}", body);
+        assert!(body.contains("<dt>field</dt><dd>password</dd>"), "{}", body);
+
+        let mut http_error = error();
+        http_error.request.headers_mut().set_raw("Accept", "text/plain");
+        let (_, body) = render(&handler, http_error);
+        assert_eq!(body, "401 Unauthorized\nInvalid <credentials>\nfield: password\n");
+    }
+
+    #[test]
+    fn test_templates() {
+        let config_path: PathBuf = [
+            env::var("CARGO_MANIFEST_DIR").unwrap().as_str(),
+            "tests",
+            "out",
+            "error_templates",
+        ].iter()
+            .collect();
+        let errors = config_path.join("errors");
+        create_dir_all(&errors).unwrap();
+        for (name, template) in [
+            ("404.html", "<p>{{path}} is {{reason}} ({{status}})</p>"),
+            ("404.json", "{\"path\":{{path}},\"code\":{{status}}}"),
+            ("403.html", "{{message}} {{path}} {{unknown}} {{ {{status}}"),
+            ("notes.txt", "ignored"),
+        ] {
+            File::create(errors.join(name))
+                .unwrap()
+                .write_all(template.as_bytes())
+                .unwrap();
+        }
+        let handler = DefaultErrorHandler::new().with_templates(&config_path);
+        assert_eq!(handler.templates.len(), 3);
+
+        assert_eq!(
+            render(&handler, get_error("/<x>", None, StatusCode::NotFound)),
+            (
+                String::from("text/html"),
+                String::from("<p>/&lt;x&gt; is Not Found (404)</p>")
+            )
+        );
+        let accept = Some("application/problem+json");
+        assert_eq!(
+            render(&handler, get_error("/x", accept, StatusCode::NotFound)),
+            (
+                String::from("application/problem+json"),
+                String::from("{\"path\":\"/x\",\"code\":404}")
+            )
+        );
+        // Placeholders are only replaced in the template, not in the values
+        let http_error =
+            get_error("/x", None, StatusCode::Forbidden).with_message("{{path}} {{status}}");
+        assert_eq!(
+            render(&handler, http_error).1,
+            "{{path}} {{status}} /x {{unknown}} {{ 403"
+        );
+        // No template for this status
+        let (_, body) = render(&handler, get_error("/x", None, StatusCode::Gone));
+        assert!(body.contains("<h1>410 Gone</h1>"), "{}", body);
+    }
+}
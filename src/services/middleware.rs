@@ -0,0 +1,273 @@
+use HttpError;
+use services::{RouteContext, Router};
+
+use futures::future::{ok, Future};
+use hyper::{Method, StatusCode};
+use hyper::server::{Request as HyperRequest, Response as HyperResponse};
+
+use std::rc::Rc;
+
+/// A `Middleware` adds cross-cutting behaviour (request ids, headers, timing, authentication,
+/// ...) around a [`RouterService`](struct.RouterService.html#method.with_middleware) or around
+/// a single router, wrapped in a [`MiddlewareRouter`](struct.MiddlewareRouter.html).
+///
+/// Both hooks receive the [`RouteContext`](struct.RouteContext.html) of the request, which can
+/// be used to pass values from `before` to `after`, or to the routers.
+pub trait Middleware {
+    /// Called before the request is handled. The returned request is handled in place of
+    /// `req`. If the future resolves to an error, the request is not routed and the response is
+    /// rendered by the error handler.
+    ///
+    /// The default implementation returns the request unchanged.
+    fn before(
+        &self,
+        req: HyperRequest,
+        _ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperRequest, Error = HttpError>> {
+        Box::new(ok(req))
+    }
+
+    /// Called with the response, which may be changed or replaced.
+    ///
+    /// Around a `RouterService`, this is also called on the responses rendered by the error
+    /// handler. Around a [`MiddlewareRouter`](struct.MiddlewareRouter.html), this is only called
+    /// on the responses returned by the wrapped router.
+    ///
+    /// The default implementation returns the response unchanged.
+    fn after(&self, res: HyperResponse, _ctx: &RouteContext) -> HyperResponse {
+        res
+    }
+}
+
+/// Calls the `before` hook of every middleware, in order.
+pub(crate) fn run_before(
+    middlewares: &[Rc<dyn Middleware>],
+    req: HyperRequest,
+    ctx: &RouteContext,
+) -> Box<dyn Future<Item = HyperRequest, Error = HttpError>> {
+    middlewares.iter().fold(Box::new(ok(req)), |before, middleware| {
+        let middleware = Rc::clone(middleware);
+        let ctx = ctx.clone();
+        Box::new(before.and_then(move |req| middleware.before(req, &ctx)))
+    })
+}
+
+/// Calls the `after` hook of every middleware, in reverse order.
+pub(crate) fn run_after(
+    middlewares: &[Rc<dyn Middleware>],
+    res: HyperResponse,
+    ctx: &RouteContext,
+) -> HyperResponse {
+    middlewares
+        .iter()
+        .rev()
+        .fold(res, |res, middleware| middleware.after(res, ctx))
+}
+
+/// A `MiddlewareRouter` wraps a [`Router`](trait.Router.html) with middlewares that only apply to
+/// the requests it accepts.
+///
+/// Routing is delegated to the wrapped router, with the request as received. The `before`
+/// hooks are called, in order, before `dispatch`, and the `after` hooks, in reverse order, on
+/// the response it returns.
+///
+/// A `MiddlewareRouter` has no error handler: errors returned by `before` hooks or by `dispatch`
+/// are passed on as they are, without calling the `after` hooks of its middlewares. They are
+/// rendered by the error handler of the `RouterService`, whose own middlewares then see the
+/// response. Middlewares which must see every response should be added to the `RouterService`.
+pub struct MiddlewareRouter {
+    router: Rc<dyn Router>,
+    middlewares: Rc<Vec<Rc<dyn Middleware>>>,
+}
+
+impl MiddlewareRouter {
+    /// Wraps `router`, without middlewares.
+    pub fn new(router: Rc<dyn Router>) -> MiddlewareRouter {
+        MiddlewareRouter {
+            router,
+            middlewares: Rc::new(Vec::new()),
+        }
+    }
+
+    /// Adds a middleware around the wrapped router.
+    pub fn with_middleware(mut self, middleware: Rc<dyn Middleware>) -> MiddlewareRouter {
+        Rc::make_mut(&mut self.middlewares).push(middleware);
+        self
+    }
+}
+
+impl Router for MiddlewareRouter {
+    fn route(
+        &self,
+        req: &HyperRequest,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+        self.router.route(req, ctx)
+    }
+
+    fn dispatch(
+        &self,
+        req: HyperRequest,
+        status_code: StatusCode,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+        let router = Rc::clone(&self.router);
+        let middlewares = Rc::clone(&self.middlewares);
+        let dispatch_ctx = ctx.clone();
+        let after_ctx = ctx.clone();
+        Box::new(
+            run_before(&self.middlewares, req, ctx)
+                .and_then(move |req| router.dispatch(req, status_code, &dispatch_ctx))
+                .map(move |res| run_after(&middlewares, res, &after_ctx)),
+        )
+    }
+
+    fn route_pattern(&self) -> Option<String> {
+        self.router.route_pattern()
+    }
+
+    fn allowed_methods(&self) -> Option<Vec<Method>> {
+        self.router.allowed_methods()
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use futures::future::err;
+    use hyper::header::Authorization;
+    use services::PatternRouter;
+    use std::cell::RefCell;
+    use std::str::from_utf8;
+
+    /// Records the hooks called, and tags requests and responses with a header.
+    struct Tagger {
+        name: &'static str,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Middleware for Tagger {
+        fn before(
+            &self,
+            mut req: HyperRequest,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperRequest, Error = HttpError>> {
+            self.log.borrow_mut().push(format!("before {}", self.name));
+            req.headers_mut().set_raw("X-Tag", self.name);
+            Box::new(ok(req))
+        }
+
+        fn after(&self, mut res: HyperResponse, _ctx: &RouteContext) -> HyperResponse {
+            self.log.borrow_mut().push(format!("after {}", self.name));
+            res.headers_mut().append_raw("X-Tag", self.name);
+            res
+        }
+    }
+
+    /// Rejects requests without `Authorization` header.
+    struct Auth;
+
+    impl Middleware for Auth {
+        fn before(
+            &self,
+            req: HyperRequest,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperRequest, Error = HttpError>> {
+            if req.headers().has::<Authorization<String>>() {
+                Box::new(ok(req))
+            } else {
+                Box::new(err(HttpError::new(req, StatusCode::Unauthorized)))
+            }
+        }
+    }
+
+    fn get_router() -> Rc<dyn Router> {
+        Rc::new(PatternRouter::new("/echo", |req, _params| {
+            let tag = req.headers()
+                .get_raw("X-Tag")
+                .and_then(|tag| tag.one())
+                .map(|tag| from_utf8(tag).unwrap().to_owned())
+                .unwrap_or_default();
+            Box::new(ok(HyperResponse::new().with_body(tag)))
+        }))
+    }
+
+    fn get_request() -> HyperRequest {
+        let uri = "https://www.rss-server.org/echo".parse().unwrap();
+        HyperRequest::new(Method::Get, uri)
+    }
+
+    fn get_tags(res: &HyperResponse) -> Vec<String> {
+        res.headers()
+            .get_raw("X-Tag")
+            .map(|tags| {
+                tags.iter()
+                    .map(|tag| from_utf8(tag).unwrap().to_owned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_middleware_router_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let router = MiddlewareRouter::new(get_router())
+            .with_middleware(Rc::new(Tagger {
+                name: "outer",
+                log: Rc::clone(&log),
+            }))
+            .with_middleware(Rc::new(Tagger {
+                name: "inner",
+                log: Rc::clone(&log),
+            }));
+        assert_eq!(router.route_pattern(), Some(String::from("/echo")));
+
+        let ctx = RouteContext::new();
+        let req = get_request();
+        assert_eq!(router.route(&req, &ctx).wait(), Ok(StatusCode::Ok));
+        let res = router.dispatch(req, StatusCode::Ok, &ctx).wait().unwrap();
+
+        assert_eq!(
+            *log.borrow(),
+            vec!["before outer", "before inner", "after inner", "after outer"]
+        );
+        assert_eq!(get_tags(&res), vec!["inner", "outer"]);
+        let body = res.body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"inner");
+    }
+
+    #[test]
+    fn test_middleware_router_rejects() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let router = MiddlewareRouter::new(get_router())
+            .with_middleware(Rc::new(Tagger {
+                name: "outer",
+                log: Rc::clone(&log),
+            }))
+            .with_middleware(Rc::new(Auth));
+
+        let error = router
+            .dispatch(get_request(), StatusCode::Ok, &RouteContext::new())
+            .wait()
+            .err()
+            .unwrap();
+        assert_eq!(error.status_code, StatusCode::Unauthorized);
+        // Errors skip the after hooks
+        assert_eq!(*log.borrow(), vec!["before outer"]);
+
+        let mut req = get_request();
+        req.headers_mut()
+            .set(Authorization(String::from("secret")));
+        let res = router
+            .dispatch(req, StatusCode::Ok, &RouteContext::new())
+            .wait()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(
+            *log.borrow(),
+            vec!["before outer", "before outer", "after outer"]
+        );
+    }
+}
@@ -1,3 +1,23 @@
+mod autoindex;
+mod context;
+mod error_handler;
+mod middleware;
+mod mount;
+mod negotiation;
+mod pattern_router;
+mod range;
+mod route_index;
 mod routing;
+mod static_router;
+mod virtual_host;
 
+pub use self::context::RouteContext;
+pub use self::error_handler::DefaultErrorHandler;
+pub use self::middleware::{Middleware, MiddlewareRouter};
+pub use self::mount::{MountRouter, OriginalUri};
+pub use self::negotiation::{Negotiated, Negotiator};
+pub use self::pattern_router::{Params, PatternHandler, PatternRouter};
+pub use self::range::{range_response, ByteRanges, ChunkStream, RangeSource};
 pub use self::routing::{ErrorHandler, ResponseFuture, Router, RouterService, RssService};
+pub use self::static_router::{ETagStrategy, StaticConfig, StaticRouterService, SymlinkPolicy};
+pub use self::virtual_host::{VirtualHostService, VirtualHostsConfig};
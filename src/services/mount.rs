@@ -0,0 +1,337 @@
+use HttpError;
+use services::{RouteContext, Router, RouterService};
+use services::routing::{dispatch_to, shadow_request, Resolution, RouteResolver};
+
+use futures::future::{err, ok, Future};
+use hyper::{StatusCode, Uri};
+use hyper::server::{Request as HyperRequest, Response as HyperResponse};
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_MOUNT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The URI of a request as received by the server, stored in the
+/// [`RouteContext`](struct.RouteContext.html) by the outermost
+/// [`MountRouter`](struct.MountRouter.html) accepting it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OriginalUri(pub Uri);
+
+/// The router chosen by every `MountRouter` that accepted the request, by mount id.
+#[derive(Clone, Default)]
+struct MountSelections(HashMap<usize, Rc<dyn Router>>);
+
+enum Mounted {
+    Routers(Rc<Vec<Rc<dyn Router>>>),
+    Service(Rc<RouterService>),
+}
+
+/// A `MountRouter` serves a group of routers, or a whole [`RouterService`](struct.RouterService.html),
+/// under a path prefix such as `/api/v1`.
+///
+/// Requests whose path is the prefix, or starts with the prefix followed by `/`, are handed to
+/// the mounted routers with the prefix stripped from the path: `/api/v1/users/42` is seen as
+/// `/users/42`, and `/api/v1` as `/`. The query string is kept. Other requests are rejected with
+/// `NotFound`. The original URI is available in the context as [`OriginalUri`](struct.OriginalUri.html).
+///
+/// Mounted routers are tried in order, as by a `RouterService`. They are routed with a copy of
+/// the request that has no body.
+pub struct MountRouter {
+    id: usize,
+    prefix: String,
+    mounted: Mounted,
+}
+
+impl MountRouter {
+    /// Mounts a group of `routers` under `prefix`.
+    pub fn new(prefix: &str, routers: Vec<Rc<dyn Router>>) -> MountRouter {
+        MountRouter::mount(prefix, Mounted::Routers(Rc::new(routers)))
+    }
+
+    /// Mounts a whole `RouterService` under `prefix`. Every request under the prefix is accepted
+    /// and handled by `service`, with its own middlewares and error handler.
+    pub fn service(prefix: &str, service: RouterService) -> MountRouter {
+        MountRouter::mount(prefix, Mounted::Service(Rc::new(service)))
+    }
+
+    fn mount(prefix: &str, mounted: Mounted) -> MountRouter {
+        MountRouter {
+            id: NEXT_MOUNT_ID.fetch_add(1, Ordering::SeqCst),
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            mounted,
+        }
+    }
+
+    /// Returns the URI of `req` relative to the prefix, or `None` if `req` is not under it.
+    fn strip_prefix(&self, req: &HyperRequest) -> Option<Uri> {
+        let rest = req.path().strip_prefix(self.prefix.as_str())?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        let mut uri = String::new();
+        if let (Some(scheme), Some(authority)) = (req.uri().scheme(), req.uri().authority()) {
+            uri.push_str(&format!("{}://{}", scheme, authority));
+        }
+        uri.push_str(if rest.is_empty() { "/" } else { rest });
+        if let Some(query) = req.query() {
+            uri.push('?');
+            uri.push_str(query);
+        }
+        uri.parse().ok()
+    }
+}
+
+impl Router for MountRouter {
+    fn route(
+        &self,
+        req: &HyperRequest,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+        let uri = match self.strip_prefix(req) {
+            Some(uri) => uri,
+            None => return Box::new(err(StatusCode::NotFound)),
+        };
+        let routers = match self.mounted {
+            Mounted::Routers(ref routers) => routers,
+            Mounted::Service(_) => {
+                if !ctx.contains::<OriginalUri>() {
+                    ctx.insert(OriginalUri(req.uri().clone()));
+                }
+                return Box::new(ok(StatusCode::Ok));
+            }
+        };
+        let id = self.id;
+        let original_uri = req.uri().clone();
+        let resolution_ctx = ctx.clone();
+        Box::new(
+            RouteResolver::new(routers)
+                .resolve(shadow_request(req, uri), ctx.clone())
+                .then(move |resolution: Result<Resolution, Infallible>| match resolution {
+                    Ok((route_resolver, _, Ok(status_code))) => {
+                        let mut selections = resolution_ctx
+                            .get::<MountSelections>()
+                            .unwrap_or_default();
+                        if let Some(router) = route_resolver.get_router() {
+                            selections.0.insert(id, router);
+                        }
+                        resolution_ctx.insert(selections);
+                        if !resolution_ctx.contains::<OriginalUri>() {
+                            resolution_ctx.insert(OriginalUri(original_uri));
+                        }
+                        Ok(status_code)
+                    }
+                    Ok((_, _, Err(status_code))) => Err(status_code),
+                    Err(never) => match never {},
+                }),
+        )
+    }
+
+    fn dispatch(
+        &self,
+        mut req: HyperRequest,
+        status_code: StatusCode,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+        let uri = match self.strip_prefix(&req) {
+            Some(uri) => uri,
+            None => return Box::new(err(HttpError::new(req, StatusCode::NotFound))),
+        };
+        match self.mounted {
+            Mounted::Routers(_) => {
+                req.set_uri(uri);
+                let router = ctx.with(|selections: &MountSelections| {
+                    selections.0.get(&self.id).map(Rc::clone)
+                });
+                match router {
+                    Some(Some(router)) => dispatch_to(&router, req, status_code, ctx),
+                    _ => Box::new(err(HttpError::new(req, StatusCode::NotFound))),
+                }
+            }
+            Mounted::Service(ref service) => {
+                // Kept to report errors, rendered by the outer error handler
+                let method = req.method().clone();
+                let original_uri = req.uri().clone();
+                req.set_uri(uri);
+                Box::new(service.call_with_context(req, ctx.clone()).map_err(move |e| {
+                    HttpError::new(
+                        HyperRequest::new(method, original_uri),
+                        StatusCode::InternalServerError,
+                    ).with_source(e)
+                }))
+            }
+        }
+    }
+
+    fn route_pattern(&self) -> Option<String> {
+        Some(format!("{}/*rest", self.prefix))
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use hyper::Error as HyperError;
+    use hyper::Method;
+    use hyper::server::Service as HyperService;
+    use services::{ErrorHandler, PatternRouter, ResponseFuture};
+    use std::str::from_utf8;
+
+    /// Renders the request path and the original URI.
+    fn get_router(pattern: &str) -> Rc<dyn Router> {
+        let name = pattern.to_owned();
+        Rc::new(PatternRouter::new(pattern, move |req, _params| {
+            Box::new(ok(HyperResponse::new().with_body(format!(
+                "{} {}",
+                name,
+                req.uri()
+            ))))
+        }))
+    }
+
+    struct PathErrorHandler;
+
+    impl ErrorHandler for PathErrorHandler {
+        fn dispatch(&self, error: HttpError) -> ResponseFuture {
+            let original = error
+                .context
+                .get::<OriginalUri>()
+                .map_or_else(String::new, |uri| uri.0.to_string());
+            let content = format!(
+                "{} {} {}",
+                error.status_code.as_u16(),
+                error.request.path(),
+                original
+            );
+            Box::new(ok(HyperResponse::new()
+                .with_status(error.status_code)
+                .with_body(content)))
+        }
+    }
+
+    fn get_service() -> RouterService {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(PathErrorHandler);
+        let api = MountRouter::new(
+            "/api/v1/",
+            vec![get_router("/users/:id"), get_router("/")],
+        );
+        let admin_service =
+            RouterService::new(vec![get_router("/stats")], &error_handler).with_route_index();
+        let admin = MountRouter::service("/admin", admin_service);
+        RouterService::new(
+            vec![Rc::new(api), Rc::new(admin), get_router("/api/v1x")],
+            &error_handler,
+        ).with_route_index()
+    }
+
+    fn get_body(service: &RouterService, uri: &str) -> (StatusCode, String) {
+        let uri = format!("https://www.rss-server.org{}", uri).parse().unwrap();
+        let response = service
+            .call(HyperRequest::new(Method::Get, uri))
+            .wait()
+            .unwrap();
+        let status_code = response.status();
+        let body = response.body().concat2().wait().unwrap();
+        (status_code, from_utf8(&body).unwrap().to_owned())
+    }
+
+    #[test]
+    fn test_mount_routers() {
+        let service = get_service();
+        assert_eq!(
+            get_body(&service, "/api/v1/users/42?full=1"),
+            (
+                StatusCode::Ok,
+                String::from("/users/:id https://www.rss-server.org/users/42?full=1")
+            )
+        );
+        assert_eq!(
+            get_body(&service, "/api/v1"),
+            (StatusCode::Ok, String::from("/ https://www.rss-server.org/"))
+        );
+        assert_eq!(
+            get_body(&service, "/api/v1x"),
+            (
+                StatusCode::Ok,
+                String::from("/api/v1x https://www.rss-server.org/api/v1x")
+            )
+        );
+        assert_eq!(
+            get_body(&service, "/api/v1/groups/7"),
+            (StatusCode::NotFound, String::from("404 /api/v1/groups/7 "))
+        );
+    }
+
+    #[test]
+    fn test_mount_service() {
+        let service = get_service();
+        assert_eq!(
+            get_body(&service, "/admin/stats"),
+            (
+                StatusCode::Ok,
+                String::from("/stats https://www.rss-server.org/stats")
+            )
+        );
+        // Errors are rendered by the mounted service, which knows the original URI
+        assert_eq!(
+            get_body(&service, "/admin/missing"),
+            (
+                StatusCode::NotFound,
+                String::from("404 /missing https://www.rss-server.org/admin/missing")
+            )
+        );
+    }
+
+    /// Fails to render any error.
+    struct FailingErrorHandler;
+
+    impl ErrorHandler for FailingErrorHandler {
+        fn dispatch(&self, _error: HttpError) -> ResponseFuture {
+            Box::new(err(HyperError::Incomplete))
+        }
+    }
+
+    #[test]
+    fn test_mount_failing_service() {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(PathErrorHandler);
+        let failing: Rc<dyn ErrorHandler> = Rc::new(FailingErrorHandler);
+        let admin_service = RouterService::new(vec![get_router("/stats")], &failing);
+        let admin = MountRouter::service("/admin", admin_service);
+        let service = RouterService::new(vec![Rc::new(admin)], &error_handler);
+
+        // Errors of the mounted service are rendered by the outer error handler
+        assert_eq!(
+            get_body(&service, "/admin/missing"),
+            (
+                StatusCode::InternalServerError,
+                String::from("500 /admin/missing https://www.rss-server.org/admin/missing")
+            )
+        );
+    }
+
+    #[test]
+    fn test_nested_mounts() {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(PathErrorHandler);
+        let inner = MountRouter::new("/v2", vec![get_router("/items/*rest")]);
+        let outer = MountRouter::new("/api", vec![get_router("/health"), Rc::new(inner)]);
+        let service = RouterService::new(vec![Rc::new(outer)], &error_handler);
+
+        assert_eq!(
+            get_body(&service, "/api/v2/items/a/b"),
+            (
+                StatusCode::Ok,
+                String::from("/items/*rest https://www.rss-server.org/items/a/b")
+            )
+        );
+        assert_eq!(
+            get_body(&service, "/api/health"),
+            (
+                StatusCode::Ok,
+                String::from("/health https://www.rss-server.org/health")
+            )
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use HttpError;
+
+use hyper::StatusCode;
+use hyper::mime::Mime;
+use hyper::server::{Request as HyperRequest, Response as HyperResponse};
+
+use std::str::from_utf8;
+
+/// An item of an `Accept*` header, such as `text/html;level=1;q=0.8`.
+#[derive(Debug, PartialEq)]
+struct Preference {
+    /// Lowercased value, without parameters
+    value: String,
+    /// Lowercased parameters other than `q`
+    params: Vec<(String, String)>,
+    /// Quality, in thousandths
+    quality: u16,
+}
+
+/// Parses a quality value such as `0.8`, returning it in thousandths.
+fn parse_quality(value: &str) -> Option<u16> {
+    let mut parts = value.splitn(2, '.');
+    let units = parts.next()?;
+    let decimals = parts.next().unwrap_or("");
+    if decimals.len() > 3 || !decimals.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", decimals).parse::<u16>().ok()?;
+    match units {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Returns the items of the `name` header of `req`, or `None` if the header is missing or
+/// empty. Malformed items are ignored.
+fn preferences(req: &HyperRequest, name: &str) -> Option<Vec<Preference>> {
+    let raw = req.headers().get_raw(name)?;
+    let mut preferences = Vec::new();
+    for line in raw.iter().filter_map(|line| from_utf8(line).ok()) {
+        'items: for item in line.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let value = match parts.next() {
+                Some(value) if !value.is_empty() => value.to_lowercase(),
+                _ => continue,
+            };
+            let mut preference = Preference {
+                value,
+                params: Vec::new(),
+                quality: 1000,
+            };
+            for param in parts {
+                let mut param = param.splitn(2, '=');
+                let name = param.next().unwrap_or("").trim().to_lowercase();
+                let value = param.next().unwrap_or("").trim().trim_matches('"');
+                if name == "q" {
+                    match parse_quality(value) {
+                        Some(quality) => preference.quality = quality,
+                        None => continue 'items,
+                    }
+                } else if !name.is_empty() {
+                    preference.params.push((name, value.to_lowercase()));
+                }
+            }
+            preferences.push(preference);
+        }
+    }
+    if preferences.is_empty() {
+        None
+    } else {
+        Some(preferences)
+    }
+}
+
+/// Returns the first of the `offered` values with the highest quality, according to the
+/// `name` header of `req`. `specificity` tells whether a preference matches an offered value,
+/// and how specific it is: the most specific matching preference gives the quality.
+///
+/// Returns `Ok(None)` if nothing is offered, the first offered value if the header is missing,
+/// and `Err(())` if no offered value is acceptable.
+fn best<'a, T, F>(
+    req: &HyperRequest,
+    name: &str,
+    offered: &'a [T],
+    specificity: F,
+) -> Result<Option<&'a T>, ()>
+where
+    F: Fn(&Preference, &T) -> Option<usize>,
+{
+    if offered.is_empty() {
+        return Ok(None);
+    }
+    let preferences = match preferences(req, name) {
+        Some(preferences) => preferences,
+        None => return Ok(offered.first()),
+    };
+    let quality = |value: &T| {
+        preferences
+            .iter()
+            .filter_map(|preference| {
+                specificity(preference, value).map(|specificity| (specificity, preference.quality))
+            })
+            .max_by_key(|&(specificity, _)| specificity)
+            .map_or(0, |(_, quality)| quality)
+    };
+    let mut selected: Option<(&T, u16)> = None;
+    for value in offered {
+        let quality = quality(value);
+        if quality > selected.map_or(0, |(_, selected)| selected) {
+            selected = Some((value, quality));
+        }
+    }
+    selected.map(|(value, _)| Some(value)).ok_or(())
+}
+
+fn media_type_specificity(preference: &Preference, offered: &Mime) -> Option<usize> {
+    let mut range = preference.value.splitn(2, '/');
+    let (type_, subtype) = (range.next()?, range.next()?);
+    let offered_type = offered.type_().as_str().to_lowercase();
+    let offered_subtype = match offered.suffix() {
+        Some(suffix) => format!("{}+{}", offered.subtype(), suffix),
+        None => offered.subtype().to_string(),
+    }.to_lowercase();
+    let specificity = match (type_, subtype) {
+        ("*", "*") => 0,
+        (type_, "*") if type_ == offered_type => 1,
+        (type_, subtype) if type_ == offered_type && subtype == offered_subtype => 2,
+        _ => return None,
+    };
+    let has_param = |(name, value): &(String, String)| {
+        offered.params().any(|(offered_name, offered_value)| {
+            offered_name.as_str().eq_ignore_ascii_case(name)
+                && offered_value.as_str().eq_ignore_ascii_case(value)
+        })
+    };
+    if preference.params.iter().all(has_param) {
+        Some(specificity * 100 + preference.params.len())
+    } else {
+        None
+    }
+}
+
+fn language_specificity(preference: &Preference, offered: &str) -> Option<usize> {
+    let offered = offered.to_lowercase();
+    if preference.value == "*" {
+        Some(0)
+    } else if offered == preference.value
+        || offered.starts_with(&format!("{}-", preference.value))
+    {
+        Some(preference.value.len())
+    } else {
+        None
+    }
+}
+
+fn charset_specificity(preference: &Preference, offered: &str) -> Option<usize> {
+    if preference.value == "*" {
+        Some(0)
+    } else if offered.eq_ignore_ascii_case(&preference.value) {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// The representation chosen by a [`Negotiator`](struct.Negotiator.html). Each field is `None`
+/// when nothing was offered for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Negotiated {
+    pub media_type: Option<Mime>,
+    pub language: Option<String>,
+    pub charset: Option<String>,
+}
+
+/// A `Negotiator` picks the representation a router should send back, among the media types,
+/// languages and charsets it offers, according to the `Accept`, `Accept-Language` and
+/// `Accept-Charset` headers of a request (RFC 7231, section 5.3).
+///
+/// Offered values are listed in order of preference: among the values of equal quality for
+/// the client, the first one wins. A missing header accepts anything, so the first offered
+/// value is chosen. Media ranges (`text/*`, `*/*`) and language prefixes (`en` matches
+/// `en-US`) are supported, the most specific matching item giving the quality. Items with
+/// `q=0` exclude the values they match.
+///
+/// A router usually builds its negotiator once, and calls
+/// [`negotiate`](#method.negotiate) from [`Router::dispatch`](trait.Router.html#tymethod.dispatch):
+/// the `NotAcceptable` error it may return is rendered by the error handler.
+#[derive(Clone, Debug, Default)]
+pub struct Negotiator {
+    media_types: Vec<Mime>,
+    languages: Vec<String>,
+    charsets: Vec<String>,
+}
+
+impl Negotiator {
+    /// Creates a negotiator offering nothing, and accepting every request.
+    pub fn new() -> Negotiator {
+        Negotiator::default()
+    }
+
+    /// Offers the given media types, negotiated with the `Accept` header.
+    pub fn with_media_types(mut self, media_types: Vec<Mime>) -> Negotiator {
+        self.media_types = media_types;
+        self
+    }
+
+    /// Offers the given language tags, negotiated with the `Accept-Language` header.
+    pub fn with_languages(mut self, languages: &[&str]) -> Negotiator {
+        self.languages = languages.iter().map(|&language| language.to_owned()).collect();
+        self
+    }
+
+    /// Offers the given charsets, negotiated with the `Accept-Charset` header.
+    pub fn with_charsets(mut self, charsets: &[&str]) -> Negotiator {
+        self.charsets = charsets.iter().map(|&charset| charset.to_owned()).collect();
+        self
+    }
+
+    /// Returns the best representation for `req`, or `None` if one of the offered dimensions
+    /// has no acceptable value.
+    pub fn select(&self, req: &HyperRequest) -> Option<Negotiated> {
+        let media_type = best(req, "Accept", &self.media_types, media_type_specificity).ok()?;
+        let language = best(req, "Accept-Language", &self.languages, |preference, language| {
+            language_specificity(preference, language)
+        }).ok()?;
+        let charset = best(req, "Accept-Charset", &self.charsets, |preference, charset| {
+            charset_specificity(preference, charset)
+        }).ok()?;
+        Some(Negotiated {
+            media_type: media_type.cloned(),
+            language: language.cloned(),
+            charset: charset.cloned(),
+        })
+    }
+
+    /// Returns the best representation for `req`, along with the request, or a `NotAcceptable`
+    /// error if one of the offered dimensions has no acceptable value.
+    #[allow(clippy::result_large_err)]
+    pub fn negotiate(&self, req: HyperRequest) -> Result<(HyperRequest, Negotiated), HttpError> {
+        match self.select(&req) {
+            Some(negotiated) => Ok((req, negotiated)),
+            None => Err(HttpError::new(req, StatusCode::NotAcceptable)),
+        }
+    }
+
+    /// Adds the negotiated headers to the `Vary` header of `res`, so that caches keep the
+    /// representations apart.
+    pub fn set_vary(&self, res: &mut HyperResponse) {
+        let mut headers = Vec::new();
+        if !self.media_types.is_empty() {
+            headers.push("Accept");
+        }
+        if !self.languages.is_empty() {
+            headers.push("Accept-Language");
+        }
+        if !self.charsets.is_empty() {
+            headers.push("Accept-Charset");
+        }
+        if !headers.is_empty() {
+            res.headers_mut().append_raw("Vary", headers.join(", "));
+        }
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Method;
+    use hyper::mime;
+
+    fn get_request(headers: &[(&'static str, &'static str)]) -> HyperRequest {
+        let uri = "https://www.rss-server.org/".parse().unwrap();
+        let mut req = HyperRequest::new(Method::Get, uri);
+        for &(name, value) in headers {
+            req.headers_mut().append_raw(name, value);
+        }
+        req
+    }
+
+    fn get_negotiator() -> Negotiator {
+        Negotiator::new()
+            .with_media_types(vec![
+                mime::APPLICATION_JSON,
+                mime::TEXT_HTML,
+                "application/xml".parse().unwrap(),
+            ])
+            .with_languages(&["en-US", "it", "fr-CA"])
+    }
+
+    fn select(headers: &[(&'static str, &'static str)]) -> Option<(String, String)> {
+        get_negotiator()
+            .select(&get_request(headers))
+            .map(|negotiated| {
+                (
+                    negotiated.media_type.unwrap().to_string(),
+                    negotiated.language.unwrap(),
+                )
+            })
+    }
+
+    #[test]
+    fn test_parse_preferences() {
+        let req = get_request(&[
+            ("Accept", "text/html;level=1;q=0.5, */*;q=0.1"),
+            ("Accept", "application/json;q=1.5, TEXT/Plain ;q=0"),
+        ]);
+        let expected = vec![
+            Preference {
+                value: String::from("text/html"),
+                params: vec![(String::from("level"), String::from("1"))],
+                quality: 500,
+            },
+            Preference {
+                value: String::from("*/*"),
+                params: Vec::new(),
+                quality: 100,
+            },
+            Preference {
+                value: String::from("text/plain"),
+                params: Vec::new(),
+                quality: 0,
+            },
+        ];
+        assert_eq!(preferences(&req, "Accept"), Some(expected));
+        assert_eq!(preferences(&req, "Accept-Language"), None);
+        for (value, expected) in [
+            ("1", Some(1000)),
+            ("1.000", Some(1000)),
+            ("0.8", Some(800)),
+            ("0.125", Some(125)),
+            ("0.1250", None),
+            ("1.5", None),
+            ("", None),
+        ] {
+            assert_eq!(parse_quality(value), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let selected = |media_type: &str, language: &str| {
+            Some((String::from(media_type), String::from(language)))
+        };
+        assert_eq!(select(&[]), selected("application/json", "en-US"));
+        assert_eq!(
+            select(&[("Accept", "text/html, application/json;q=0.9")]),
+            selected("text/html", "en-US")
+        );
+        assert_eq!(
+            select(&[("Accept", "text/*;q=0.5, application/*;q=0.5")]),
+            selected("application/json", "en-US")
+        );
+        // The most specific range gives the quality
+        assert_eq!(
+            select(&[("Accept", "application/json;q=0, */*")]),
+            selected("text/html", "en-US")
+        );
+        assert_eq!(
+            select(&[("Accept-Language", "fr, it;q=0.8, *;q=0.1")]),
+            selected("application/json", "fr-CA")
+        );
+        assert_eq!(
+            select(&[("Accept-Language", "de, *;q=0.5, en;q=0")]),
+            selected("application/json", "it")
+        );
+        assert_eq!(select(&[("Accept", "image/png")]), None);
+        let negotiator = Negotiator::new().with_media_types(vec![
+            mime::APPLICATION_JSON,
+            "application/problem+json".parse().unwrap(),
+        ]);
+        let req = get_request(&[("Accept", "application/problem+json")]);
+        assert_eq!(
+            negotiator.select(&req).unwrap().media_type,
+            Some("application/problem+json".parse().unwrap())
+        );
+        assert_eq!(select(&[("Accept-Language", "de")]), None);
+        // Charsets are not offered, so they are not negotiated
+        assert_eq!(
+            select(&[("Accept-Charset", "iso-8859-5")]),
+            selected("application/json", "en-US")
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let negotiator = Negotiator::new().with_charsets(&["utf-8"]);
+        let (_, negotiated) = negotiator
+            .negotiate(get_request(&[("Accept-Charset", "iso-8859-5, UTF-8;q=0.5")]))
+            .unwrap();
+        assert_eq!(negotiated.charset, Some(String::from("utf-8")));
+        assert_eq!(negotiated.media_type, None);
+
+        let http_error = negotiator
+            .negotiate(get_request(&[("Accept-Charset", "iso-8859-5")]))
+            .err()
+            .unwrap();
+        assert_eq!(http_error.status_code, StatusCode::NotAcceptable);
+
+        let mut res = HyperResponse::new();
+        get_negotiator().set_vary(&mut res);
+        assert_eq!(
+            res.headers().get_raw("Vary").unwrap(),
+            "Accept, Accept-Language"
+        );
+    }
+}
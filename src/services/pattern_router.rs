@@ -0,0 +1,361 @@
+use HttpError;
+use services::{RouteContext, Router};
+
+use futures::future::{err, ok, Future};
+use hyper::{Method, StatusCode};
+use hyper::server::{Request as HyperRequest, Response as HyperResponse};
+use percent_encoding::percent_decode;
+
+/// A segment of a path template.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Segment {
+    /// Matches a segment equal to the given text
+    Literal(String),
+    /// `:name`, matches any non empty segment
+    Param(String),
+    /// `*name`, matches the rest of the path, possibly empty. Only allowed as last segment
+    Wildcard(String),
+}
+
+/// Values captured by the `:name` and `*name` segments of a [`PatternRouter`](struct.PatternRouter.html)
+/// template. Values are percent-decoded, except `%2F` and `%25` which are kept encoded: a `/`
+/// in a value always separates segments of the path, and `%` always starts an escape.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    /// Returns the value captured by the segment called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Iterates over the captured `(name, value)` pairs, in template order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Returns the number of captured values.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns `true` if the template has no `:name` or `*name` segment.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Handler invoked by a [`PatternRouter`](struct.PatternRouter.html) to render the response of
+/// a matching request.
+pub type PatternHandler =
+    dyn Fn(HyperRequest, Params) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>>;
+
+/// A `PatternRouter` is a [`Router`](trait.Router.html) matching the request path against a
+/// template such as `/users/:id/posts/*rest`.
+///
+/// - literal segments must match exactly, once the path segment is percent-decoded;
+/// - `:name` segments match any single, non empty segment;
+/// - a `*name` segment, only allowed at the end of the template, matches the rest of the path,
+///   possibly empty.
+///
+/// Empty segments are ignored on both sides, so `/users/42/` matches `/users/:id`. When the
+/// template matches, the captured [`Params`](struct.Params.html) are stored in the
+/// [`RouteContext`](struct.RouteContext.html) and passed to the handler. Otherwise the request is
+/// rejected with `NotFound`, so the next router gets a chance to handle it.
+///
+/// The [allowed methods](#method.with_methods) are declared through
+/// [`Router::allowed_methods`](trait.Router.html#method.allowed_methods), and checked by the
+/// [`RouterService`](struct.RouterService.html).
+pub struct PatternRouter {
+    pattern: String,
+    segments: Vec<Segment>,
+    methods: Option<Vec<Method>>,
+    handler: Box<PatternHandler>,
+}
+
+impl PatternRouter {
+    /// Creates a router dispatching the requests matching `pattern` to `handler`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` is not a valid template: a `:` or `*` segment without name, or a `*`
+    /// segment that is not the last one.
+    pub fn new<H>(pattern: &str, handler: H) -> PatternRouter
+    where
+        H: Fn(HyperRequest, Params) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>>
+            + 'static,
+    {
+        PatternRouter {
+            pattern: pattern.to_owned(),
+            segments: parse_pattern(pattern),
+            methods: None,
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Restricts the router to the given methods. By default every method is accepted. Routers
+    /// allowing `GET` also handle `HEAD` requests, unless they allow `HEAD` explicitly.
+    pub fn with_methods(mut self, methods: Vec<Method>) -> PatternRouter {
+        self.methods = Some(methods);
+        self
+    }
+
+    /// Returns the template of this router.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Matches the request path against the template, returning the captured values.
+    pub fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = Params::default();
+        let mut path_segments = path.split('/').filter(|segment| !segment.is_empty());
+        for segment in &self.segments {
+            match *segment {
+                Segment::Literal(ref literal) => {
+                    if decode_segment(path_segments.next()?)? != *literal {
+                        return None;
+                    }
+                }
+                Segment::Param(ref name) => {
+                    let value = decode_segment(path_segments.next()?)?;
+                    params.values.push((name.clone(), value));
+                }
+                Segment::Wildcard(ref name) => {
+                    let rest: Option<Vec<String>> =
+                        path_segments.by_ref().map(decode_segment).collect();
+                    params.values.push((name.clone(), rest?.join("/")));
+                }
+            }
+        }
+        if path_segments.next().is_some() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+/// Parses a path template into its segments.
+///
+/// # Panics
+///
+/// Panics if `pattern` is not a valid template.
+pub(crate) fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    for segment in pattern.split('/').filter(|segment| !segment.is_empty()) {
+        if let Some(&Segment::Wildcard(_)) = segments.last() {
+            panic!("invalid pattern {:?}: `*` must be the last segment", pattern);
+        }
+        segments.push(if segment == ":" || segment == "*" {
+            panic!("invalid pattern {:?}: unnamed segment {:?}", pattern, segment)
+        } else if let Some(name) = segment.strip_prefix(':') {
+            Segment::Param(name.to_owned())
+        } else if let Some(name) = segment.strip_prefix('*') {
+            Segment::Wildcard(name.to_owned())
+        } else {
+            Segment::Literal(segment.to_owned())
+        });
+    }
+    segments
+}
+
+/// Percent-decodes a path segment, keeping `%2F` and `%25` encoded. Fails if the decoded
+/// segment is not UTF-8.
+pub(crate) fn decode_segment(segment: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(segment.len());
+    let mut rest = segment;
+    while let Some(ix) = find_kept_escape(rest) {
+        decoded.push_str(&decode(&rest[..ix])?);
+        decoded.push_str(&rest[ix..ix + 3].to_uppercase());
+        rest = &rest[ix + 3..];
+    }
+    decoded.push_str(&decode(rest)?);
+    Some(decoded)
+}
+
+/// Returns the position of the first `%2F` or `%25` of `segment`, in any case.
+fn find_kept_escape(segment: &str) -> Option<usize> {
+    segment.as_bytes().windows(3).position(|escape| {
+        escape[0] == b'%' && (escape[1..].eq_ignore_ascii_case(b"2f") || &escape[1..] == b"25")
+    })
+}
+
+fn decode(value: &str) -> Option<String> {
+    percent_decode(value.as_bytes())
+        .decode_utf8()
+        .ok()
+        .map(|value| value.into_owned())
+}
+
+impl Router for PatternRouter {
+    fn route(
+        &self,
+        req: &HyperRequest,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+        match self.matches(req.path()) {
+            Some(params) => {
+                ctx.insert(params);
+                Box::new(ok(StatusCode::Ok))
+            }
+            None => Box::new(err(StatusCode::NotFound)),
+        }
+    }
+
+    fn dispatch(
+        &self,
+        req: HyperRequest,
+        status_code: StatusCode,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+        if status_code != StatusCode::Ok {
+            return Box::new(err(HttpError::new(req, status_code)));
+        }
+        match ctx.get::<Params>().or_else(|| self.matches(req.path())) {
+            Some(params) => (self.handler)(req, params),
+            None => Box::new(err(HttpError::new(req, StatusCode::NotFound))),
+        }
+    }
+
+    fn route_pattern(&self) -> Option<String> {
+        Some(self.pattern.clone())
+    }
+
+    fn allowed_methods(&self) -> Option<Vec<Method>> {
+        self.methods.clone()
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use hyper::header::ContentLength;
+
+    fn get_request(method: Method, path: &str) -> HyperRequest {
+        let uri = format!("https://www.rss-server.org{}", path).parse().unwrap();
+        HyperRequest::new(method, uri)
+    }
+
+    fn get_router(pattern: &str) -> PatternRouter {
+        PatternRouter::new(pattern, |_req, params| {
+            let content = params
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("&");
+            Box::new(ok(HyperResponse::new()
+                .with_header(ContentLength(content.len() as u64))
+                .with_body(content)))
+        })
+    }
+
+    fn params(values: &[(&str, &str)]) -> Params {
+        Params {
+            values: values
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let router = get_router("/users/:id/posts/*rest");
+        assert_eq!(
+            router.matches("/users/42/posts/2018/03/hello"),
+            Some(params(&[("id", "42"), ("rest", "2018/03/hello")]))
+        );
+        assert_eq!(
+            router.matches("/users/42/posts"),
+            Some(params(&[("id", "42"), ("rest", "")]))
+        );
+        assert_eq!(
+            router.matches("/users/j%C3%B6rg/posts/a%20b"),
+            Some(params(&[("id", "jörg"), ("rest", "a b")]))
+        );
+        assert_eq!(router.matches("/users//posts/x"), None);
+        assert_eq!(router.matches("/users/42/comments/x"), None);
+        assert_eq!(router.matches("/users/%ff/posts"), None);
+        assert_eq!(
+            router.matches("/%75sers/a%2fb/posts/c%2Fd/100%25/%2541"),
+            Some(params(&[("id", "a%2Fb"), ("rest", "c%2Fd/100%25/%2541")]))
+        );
+        assert_eq!(router.matches("/users%2F42/posts"), None);
+
+        let router = get_router("/users/:id");
+        assert_eq!(
+            router.matches("/users/42/"),
+            Some(params(&[("id", "42")]))
+        );
+        assert_eq!(router.matches("/users/42/posts"), None);
+        assert_eq!(router.matches("/users"), None);
+
+        let router = get_router("/");
+        assert_eq!(router.matches("/"), Some(Params::default()));
+        assert_eq!(router.matches("/users"), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_wildcard_not_last() {
+        get_router("/files/*path/raw");
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unnamed_param() {
+        get_router("/users/:/posts");
+    }
+
+    #[test]
+    fn test_route_methods() {
+        assert_eq!(get_router("/users/:id").allowed_methods(), None);
+        let router = get_router("/users/:id").with_methods(vec![Method::Get, Method::Put]);
+        assert_eq!(
+            router.allowed_methods(),
+            Some(vec![Method::Get, Method::Put])
+        );
+        // Methods are checked by the RouterService, routing only looks at the path
+        for method in [Method::Get, Method::Put, Method::Post] {
+            let status_code = router
+                .route(&get_request(method, "/users/42"), &RouteContext::new())
+                .wait();
+            assert_eq!(status_code, Ok(StatusCode::Ok));
+        }
+        let status_code = router
+            .route(&get_request(Method::Get, "/groups/42"), &RouteContext::new())
+            .wait();
+        assert_eq!(status_code, Err(StatusCode::NotFound));
+    }
+
+    #[test]
+    fn test_dispatch_params() {
+        let router = get_router("/users/:id/posts/*rest");
+        let req = get_request(Method::Get, "/users/42/posts/a/b");
+        let ctx = RouteContext::new();
+        assert_eq!(router.route(&req, &ctx).wait(), Ok(StatusCode::Ok));
+        assert_eq!(
+            ctx.get::<Params>(),
+            Some(params(&[("id", "42"), ("rest", "a/b")]))
+        );
+        let response = router.dispatch(req, StatusCode::Ok, &ctx).wait().unwrap();
+        let body = response.body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"id=42&rest=a/b");
+
+        // Params stored while routing are used as they are
+        let req = get_request(Method::Get, "/users/42/posts/a/b");
+        let ctx = RouteContext::new();
+        ctx.insert(params(&[("id", "7")]));
+        let response = router.dispatch(req, StatusCode::Ok, &ctx).wait().unwrap();
+        let body = response.body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"id=7");
+    }
+}
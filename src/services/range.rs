@@ -0,0 +1,413 @@
+use HttpError;
+
+use futures::{stream, Future, Sink, Stream};
+use futures::sync::mpsc::SendError;
+use hyper::{Body, Chunk, Method, StatusCode};
+use hyper::Error as HyperError;
+use hyper::header::{AcceptRanges, ContentLength, ContentRange, ContentRangeSpec, ContentType,
+                    ETag, IfRange, LastModified, Range, RangeUnit};
+use hyper::mime::Mime;
+use hyper::server::{Request as HyperRequest, Response as HyperResponse};
+use services::routing::Panic;
+use tokio_core::reactor::Handle;
+
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum number of ranges honoured in a single request. Requests asking for more ranges are
+/// answered with the whole representation.
+const MAX_RANGES: usize = 32;
+
+static BOUNDARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Boxed stream of body chunks.
+pub type ChunkStream = Box<dyn Stream<Item = Chunk, Error = HyperError>>;
+
+/// A representation whose byte ranges can be streamed independently.
+///
+/// It is implemented for `Vec<u8>`; routers serving content from other sources (files, blobs)
+/// implement it to use [`range_response`](fn.range_response.html).
+pub trait RangeSource {
+    /// Returns a stream over the bytes from `first` to `last`, both inclusive.
+    fn stream_range(&self, first: u64, last: u64) -> ChunkStream;
+}
+
+impl RangeSource for Vec<u8> {
+    fn stream_range(&self, first: u64, last: u64) -> ChunkStream {
+        let chunk = Chunk::from(self[first as usize..(last + 1) as usize].to_vec());
+        Box::new(stream::once(Ok(chunk)))
+    }
+}
+
+/// Byte ranges requested by a client, resolved against the length of a representation.
+#[derive(Debug, PartialEq)]
+pub enum ByteRanges {
+    /// No usable `Range` header: the whole representation must be sent.
+    Full,
+    /// Satisfiable ranges, as inclusive `(first, last)` offsets, sorted and coalesced.
+    Partial(Vec<(u64, u64)>),
+    /// None of the requested ranges can be satisfied.
+    Unsatisfiable,
+}
+
+impl ByteRanges {
+    /// Evaluates the `Range` and `If-Range` headers of `req` for a representation of `length`
+    /// bytes. `etag` and `last_modified` are the validators of the representation, used to
+    /// evaluate `If-Range`.
+    pub fn from_request(
+        req: &HyperRequest,
+        length: u64,
+        etag: Option<&ETag>,
+        last_modified: Option<&LastModified>,
+    ) -> ByteRanges {
+        if *req.method() != Method::Get {
+            return ByteRanges::Full;
+        }
+        let specs = match req.headers().get::<Range>() {
+            Some(Range::Bytes(specs)) if !specs.is_empty() && specs.len() <= MAX_RANGES => {
+                specs
+            }
+            _ => return ByteRanges::Full,
+        };
+        let if_range_matches = match (req.headers().get::<IfRange>(), etag, last_modified) {
+            (None, _, _) => true,
+            (Some(IfRange::EntityTag(tag)), Some(etag), _) => etag.strong_eq(tag),
+            (Some(IfRange::Date(date)), _, Some(modified)) => {
+                SystemTime::from(modified.0) == SystemTime::from(*date)
+            }
+            _ => false,
+        };
+        if !if_range_matches {
+            return ByteRanges::Full;
+        }
+
+        let mut ranges: Vec<(u64, u64)> = specs
+            .iter()
+            .filter_map(|spec| spec.to_satisfiable_range(length))
+            .collect();
+        if ranges.is_empty() {
+            return ByteRanges::Unsatisfiable;
+        }
+        ranges.sort();
+        let mut coalesced: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match coalesced.last_mut() {
+                Some(previous) if first <= previous.1 + 1 => {
+                    previous.1 = previous.1.max(last);
+                }
+                _ => coalesced.push((first, last)),
+            }
+        }
+        ByteRanges::Partial(coalesced)
+    }
+}
+
+/// Completes `res` with the content of `source`, honouring the `Range` header of `req`.
+///
+/// `res` should already carry the headers of the whole representation (`Content-Type`, `ETag`,
+/// `Last-Modified`...), which are also used to evaluate `If-Range`. Depending on the request the
+/// result is:
+///
+/// - `200` with the whole content, when no satisfiable `Range` applies;
+/// - `206` with `Content-Range`, for a single range;
+/// - `206` with a `multipart/byteranges` body, for multiple ranges;
+///
+/// When no requested range can be satisfied, an `HttpError` with status `416` and
+/// `Content-Range: bytes */<length>` is returned instead, to be rendered by the error handler.
+///
+/// The body is streamed from a task spawned on `handle`.
+#[allow(clippy::result_large_err)]
+pub fn range_response<S>(
+    req: HyperRequest,
+    res: HyperResponse,
+    length: u64,
+    source: S,
+    handle: &Handle,
+) -> Result<HyperResponse, HttpError>
+where
+    S: RangeSource,
+{
+    let ranges = ByteRanges::from_request(
+        &req,
+        length,
+        res.headers().get::<ETag>(),
+        res.headers().get::<LastModified>(),
+    );
+    let res = res.with_header(AcceptRanges(vec![RangeUnit::Bytes]));
+    match ranges {
+        ByteRanges::Full => {
+            let body = if length == 0 {
+                Body::empty()
+            } else {
+                spawn_body(handle, source.stream_range(0, length - 1))
+            };
+            Ok(res.with_header(ContentLength(length)).with_body(body))
+        }
+        ByteRanges::Partial(ref ranges) if ranges.len() == 1 => {
+            let (first, last) = ranges[0];
+            Ok(res.with_status(StatusCode::PartialContent)
+                .with_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((first, last)),
+                    instance_length: Some(length),
+                }))
+                .with_header(ContentLength(last - first + 1))
+                .with_body(spawn_body(handle, source.stream_range(first, last))))
+        }
+        ByteRanges::Partial(ranges) => {
+            Ok(multipart_response(res, length, &ranges, &source, handle))
+        }
+        ByteRanges::Unsatisfiable => Err(HttpError::new(req, StatusCode::RangeNotSatisfiable)
+            .with_header(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(length),
+            }))),
+    }
+}
+
+/// Builds a `multipart/byteranges` response, each part carrying the `Content-Type` of `res`.
+fn multipart_response<S>(
+    mut res: HyperResponse,
+    length: u64,
+    ranges: &[(u64, u64)],
+    source: &S,
+    handle: &Handle,
+) -> HyperResponse
+where
+    S: RangeSource,
+{
+    let boundary = new_boundary();
+    let content_type = res.headers_mut().remove::<ContentType>();
+
+    let mut content_length = 0;
+    let mut parts: Vec<ChunkStream> = Vec::with_capacity(ranges.len() * 2 + 1);
+    for &(first, last) in ranges {
+        let mut part_headers = format!("\r\n--{}\r\n", boundary);
+        if let Some(ContentType(ref mime)) = content_type {
+            part_headers.push_str(&format!("Content-Type: {}\r\n", mime));
+        }
+        part_headers.push_str(&format!(
+            "Content-Range: bytes {}-{}/{}\r\n\r\n",
+            first, last, length
+        ));
+        content_length += part_headers.len() as u64 + last - first + 1;
+        parts.push(Box::new(stream::once(Ok(Chunk::from(part_headers)))));
+        parts.push(source.stream_range(first, last));
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    content_length += closing.len() as u64;
+    parts.push(Box::new(stream::once(Ok(Chunk::from(closing)))));
+
+    let mime: Mime = format!("multipart/byteranges; boundary={}", boundary)
+        .parse()
+        .unwrap();
+    res.with_status(StatusCode::PartialContent)
+        .with_header(ContentType(mime))
+        .with_header(ContentLength(content_length))
+        .with_body(spawn_body(handle, Box::new(stream::iter_ok::<_, HyperError>(parts).flatten())))
+}
+
+/// Returns a boundary that is very unlikely to appear in the content.
+fn new_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0);
+    format!(
+        "rss-server-{:08x}{:08x}",
+        nanos,
+        BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Returns a `Body` fed by `chunks` from a task spawned on `handle`.
+///
+/// A panic while polling `chunks` is logged and ends the body early, instead of unwinding
+/// through the reactor.
+pub(crate) fn spawn_body(handle: &Handle, chunks: ChunkStream) -> Body {
+    let (sender, body) = Body::pair();
+    let chunks = chunks.then(Ok::<_, SendError<Result<Chunk, HyperError>>>);
+    let send = AssertUnwindSafe(sender.send_all(chunks))
+        .catch_unwind()
+        .then(|result| {
+            if let Err(payload) = result {
+                error!("streaming a body {}", Panic::new(&*payload));
+            }
+            Ok(())
+        });
+    handle.spawn(send);
+    body
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{ByteRangeSpec, EntityTag, HttpDate};
+    use tokio_core::reactor::Core;
+    use std::str::from_utf8;
+
+    fn get_request(range: Option<Range>) -> HyperRequest {
+        let uri = "https://www.rss-server.org/file.txt".parse().unwrap();
+        let mut req = HyperRequest::new(Method::Get, uri);
+        if let Some(range) = range {
+            req.headers_mut().set(range);
+        }
+        req
+    }
+
+    fn get_content() -> Vec<u8> {
+        b"0123456789abcdefghij".to_vec()
+    }
+
+    #[test]
+    fn test_byte_ranges_full() {
+        let req = get_request(None);
+        assert_eq!(ByteRanges::from_request(&req, 20, None, None), ByteRanges::Full);
+
+        let mut req = get_request(Some(Range::bytes(0, 4)));
+        req.set_method(Method::Post);
+        assert_eq!(ByteRanges::from_request(&req, 20, None, None), ByteRanges::Full);
+
+        let req = get_request(Some(Range::Unregistered(
+            String::from("lines"),
+            String::from("1-2"),
+        )));
+        assert_eq!(ByteRanges::from_request(&req, 20, None, None), ByteRanges::Full);
+    }
+
+    #[test]
+    fn test_byte_ranges_partial() {
+        let req = get_request(Some(Range::Bytes(vec![
+            ByteRangeSpec::FromTo(15, 30),
+            ByteRangeSpec::Last(3),
+            ByteRangeSpec::FromTo(0, 2),
+            ByteRangeSpec::AllFrom(3),
+        ])));
+        assert_eq!(
+            ByteRanges::from_request(&req, 20, None, None),
+            ByteRanges::Partial(vec![(0, 19)])
+        );
+
+        let req = get_request(Some(Range::bytes_multi(vec![(10, 12), (0, 1), (2, 3)])));
+        assert_eq!(
+            ByteRanges::from_request(&req, 20, None, None),
+            ByteRanges::Partial(vec![(0, 3), (10, 12)])
+        );
+
+        let req = get_request(Some(Range::bytes(25, 30)));
+        assert_eq!(
+            ByteRanges::from_request(&req, 20, None, None),
+            ByteRanges::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn test_byte_ranges_if_range() {
+        let etag = ETag(EntityTag::strong(String::from("v1")));
+        let modified = LastModified(HttpDate::from(UNIX_EPOCH));
+
+        let mut req = get_request(Some(Range::bytes(0, 4)));
+        req.headers_mut()
+            .set(IfRange::EntityTag(EntityTag::strong(String::from("v1"))));
+        assert_eq!(
+            ByteRanges::from_request(&req, 20, Some(&etag), None),
+            ByteRanges::Partial(vec![(0, 4)])
+        );
+        req.headers_mut()
+            .set(IfRange::EntityTag(EntityTag::strong(String::from("v0"))));
+        assert_eq!(
+            ByteRanges::from_request(&req, 20, Some(&etag), None),
+            ByteRanges::Full
+        );
+
+        req.headers_mut()
+            .set(IfRange::Date(HttpDate::from(UNIX_EPOCH)));
+        assert_eq!(
+            ByteRanges::from_request(&req, 20, None, Some(&modified)),
+            ByteRanges::Partial(vec![(0, 4)])
+        );
+        req.headers_mut().set(IfRange::Date(HttpDate::from(SystemTime::now())));
+        assert_eq!(
+            ByteRanges::from_request(&req, 20, None, Some(&modified)),
+            ByteRanges::Full
+        );
+    }
+
+    #[test]
+    fn test_range_response_multipart() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let req = get_request(Some(Range::bytes_multi(vec![(0, 1), (10, 12)])));
+        let res = HyperResponse::new().with_header(ContentType::plaintext());
+
+        let response = range_response(req, res, 20, get_content(), &handle).unwrap();
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        let content_type = format!("{}", response.headers().get::<ContentType>().unwrap());
+        assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        let boundary = content_type.split('=').nth(1).unwrap().to_owned();
+        let content_length = response.headers().get::<ContentLength>().unwrap().0;
+
+        let body = core.run(response.body().concat2()).unwrap();
+        let body = from_utf8(&body).unwrap();
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/20\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 10-12/20\r\n\r\nabc\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(body, expected);
+        assert_eq!(content_length, expected.len() as u64);
+    }
+
+    #[test]
+    fn test_range_response_full_and_unsatisfiable() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let response = range_response(
+            get_request(None),
+            HyperResponse::new(),
+            20,
+            get_content(),
+            &handle,
+        ).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(
+            response.headers().get::<AcceptRanges>(),
+            Some(&AcceptRanges(vec![RangeUnit::Bytes]))
+        );
+        let body = core.run(response.body().concat2()).unwrap();
+        assert_eq!(&body[..], &get_content()[..]);
+
+        let error = range_response(
+            get_request(Some(Range::bytes(20, 25))),
+            HyperResponse::new(),
+            20,
+            get_content(),
+            &handle,
+        ).err()
+            .unwrap();
+        assert_eq!(error.status_code, StatusCode::RangeNotSatisfiable);
+        assert_eq!(
+            error.headers.get_raw("Content-Range").unwrap(),
+            "bytes */20"
+        );
+    }
+
+    #[test]
+    fn test_spawn_body_panic() {
+        let mut core = Core::new().unwrap();
+        let chunks = stream::iter_ok::<_, HyperError>(vec![Chunk::from("first"), Chunk::from("")])
+            .map(|chunk| {
+                if chunk.is_empty() {
+                    panic!("broken source");
+                }
+                chunk
+            });
+
+        let body = spawn_body(&core.handle(), Box::new(chunks));
+        let body = core.run(body.concat2()).unwrap();
+        assert_eq!(&body[..], b"first");
+    }
+}
@@ -0,0 +1,166 @@
+use services::Router;
+use services::pattern_router::{decode_segment, parse_pattern, Segment};
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A node of the [`RouteIndex`] trie, reached through a sequence of path segments.
+#[derive(Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    param: Option<Box<Node>>,
+    /// Routers whose pattern ends at this node
+    routers: Vec<usize>,
+    /// Routers whose pattern ends with a wildcard at this node
+    wildcard_routers: Vec<usize>,
+}
+
+impl Node {
+    fn insert(&mut self, segments: &[Segment], ix: usize) {
+        match segments.split_first() {
+            None => self.routers.push(ix),
+            Some((Segment::Wildcard(_), _)) => self.wildcard_routers.push(ix),
+            Some((Segment::Literal(literal), rest)) => self.literals
+                .entry(literal.clone())
+                .or_default()
+                .insert(rest, ix),
+            Some((Segment::Param(_), rest)) => self.param
+                .get_or_insert_with(Box::default)
+                .insert(rest, ix),
+        }
+    }
+
+    fn collect(&self, segments: &[String], candidates: &mut Vec<usize>) {
+        candidates.extend(&self.wildcard_routers);
+        match segments.split_first() {
+            None => candidates.extend(&self.routers),
+            Some((segment, rest)) => {
+                if let Some(node) = self.literals.get(segment) {
+                    node.collect(rest, candidates);
+                }
+                if let Some(ref node) = self.param {
+                    node.collect(rest, candidates);
+                }
+            }
+        }
+    }
+}
+
+/// Index of the routers of a [`RouterService`](struct.RouterService.html), built from the
+/// patterns they declare through [`Router::route_pattern`](trait.Router.html#method.route_pattern).
+///
+/// Routers are referred to by their position in the `RouterService`, so that candidates can be
+/// tried in the same order as without index.
+pub(crate) struct RouteIndex {
+    root: Node,
+    /// Routers that don't declare a pattern, and are candidates for every request
+    unindexed: Vec<usize>,
+}
+
+impl RouteIndex {
+    /// Builds the index of `routers`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a router declares an invalid pattern.
+    pub fn new(routers: &[Rc<dyn Router>]) -> RouteIndex {
+        let mut index = RouteIndex {
+            root: Node::default(),
+            unindexed: Vec::new(),
+        };
+        for (ix, router) in routers.iter().enumerate() {
+            match router.route_pattern() {
+                Some(pattern) => index.root.insert(&parse_pattern(&pattern), ix),
+                None => index.unindexed.push(ix),
+            }
+        }
+        index
+    }
+
+    /// Returns the positions, in increasing order, of the routers that may accept a request
+    /// for `path`. Segments are decoded as by [`PatternRouter`](struct.PatternRouter.html).
+    pub fn candidates(&self, path: &str) -> Vec<usize> {
+        let segments: Vec<String> = path.split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| decode_segment(segment).unwrap_or_else(|| segment.to_owned()))
+            .collect();
+        let mut candidates = self.unindexed.clone();
+        self.root.collect(&segments, &mut candidates);
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use HttpError;
+    use futures::future::{err, Future};
+    use hyper::StatusCode;
+    use hyper::server::{Request as HyperRequest, Response as HyperResponse};
+    use services::RouteContext;
+
+    /// Router declaring a pattern, and never accepting anything.
+    struct DeclaredRouter(Option<&'static str>);
+
+    impl Router for DeclaredRouter {
+        fn route(
+            &self,
+            _req: &HyperRequest,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+            Box::new(err(StatusCode::NotFound))
+        }
+
+        fn dispatch(
+            &self,
+            req: HyperRequest,
+            status_code: StatusCode,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+            Box::new(err(HttpError::new(req, status_code)))
+        }
+
+        fn route_pattern(&self) -> Option<String> {
+            self.0.map(String::from)
+        }
+    }
+
+    #[test]
+    fn test_candidates() {
+        let patterns = [
+            Some("/users/:id"),
+            None,
+            Some("/users/me"),
+            Some("/users/:id/posts/*rest"),
+            Some("/static/*path"),
+            Some("/"),
+            Some("/users/:id/posts/:post"),
+        ];
+        let routers: Vec<Rc<dyn Router>> = patterns
+            .iter()
+            .map(|&pattern| Rc::new(DeclaredRouter(pattern)) as Rc<dyn Router>)
+            .collect();
+        let index = RouteIndex::new(&routers);
+
+        let expectations: Vec<(&str, Vec<usize>)> = vec![
+            ("/", vec![1, 5]),
+            ("/users", vec![1]),
+            ("/users/42", vec![0, 1]),
+            ("/users/me/", vec![0, 1, 2]),
+            ("/%75sers/m%65", vec![0, 1, 2]),
+            ("/users%2Fme", vec![1]),
+            ("/users/42/posts", vec![1, 3]),
+            ("/users/42/posts/7", vec![1, 3, 6]),
+            ("/users/42/posts/7/comments", vec![1, 3]),
+            ("/static", vec![1, 4]),
+            ("/static/css/site.css", vec![1, 4]),
+            ("/other/page", vec![1]),
+        ];
+        for (path, expected) in expectations {
+            assert_eq!(index.candidates(path), expected, "{}", path);
+        }
+    }
+}
@@ -1,15 +1,24 @@
 use HttpError;
 use hyper::server::{Request as HyperRequest, Response as HyperResponse, Service as HyperService};
-use std::io::Error;
+use std::any::Any;
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
 use futures::future;
 use futures::future::{err, ok, Future, Loop};
-use hyper::StatusCode;
+use hyper::{Body, Method, StatusCode, Uri};
 use hyper::Error as HyperError;
+use hyper::header::{Allow, ContentLength};
+use services::RouteContext;
+use services::Middleware;
+use services::middleware::{run_after, run_before};
+use services::route_index::RouteIndex;
 use std::rc::Rc;
 
-pub type ResponseFuture = Box<Future<Item = HyperResponse, Error = HyperError>>;
+pub type ResponseFuture = Box<dyn Future<Item = HyperResponse, Error = HyperError>>;
 
-pub type RssService = HyperService<
+pub type RssService = dyn HyperService<
     Request = HyperRequest,
     Response = HyperResponse,
     Error = HyperError,
@@ -23,9 +32,12 @@ pub type RssService = HyperService<
 ///
 /// - If the future result is NOT an error, the response generation is delegated to the [`dispatch`](trait.Router.html#tymethod.dispatch) method.
 /// - If the future result is an error, and the associated status code is `NotFound` (404) the computation is delegated to the
-/// next `Router` of [`RouterService`](struct.RouterService.html). If every route has been accepted and has failed or if the status code associated
-/// to the error is different from `NotFound`. The special Router `error_handler` of [`RouterService`](struct.RouterService.html) is used to display
-/// the error message.
+///   next `Router` of [`RouterService`](struct.RouterService.html). If every route has been accepted and has failed or if the status code associated
+///   to the error is different from `NotFound`. The special Router `error_handler` of [`RouterService`](struct.RouterService.html) is used to display
+///   the error message.
+///
+/// Both methods receive the [`RouteContext`](struct.RouteContext.html) of the request, so values
+/// computed while routing can be reused when dispatching, and by the error handler.
 ///
 /// Routers are usually passed to [`RouterService::new`](struct.RouterService.html#tymethod.new), an [Hyper](https://hyper.rs/)
 /// that performs HTTP dispatching strategy
@@ -33,7 +45,11 @@ pub trait Router {
     /// This method is used to perform the routing logic. If the status code is not returned as an error,
     /// the [`dispatch`](trait.Router.html#tymethod.dispatch) method will be called to render the HTTP
     /// response.
-    fn route(&self, req: &HyperRequest) -> Box<Future<Item = StatusCode, Error = StatusCode>>;
+    fn route(
+        &self,
+        req: &HyperRequest,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>>;
 
     /// This method processes the request and return the response asynchronously. If the future resolves to an error,
     /// the response generation is delegated to the [`RouterService`](struct.RouterService.html) `error_handler`.
@@ -41,21 +57,175 @@ pub trait Router {
         &self,
         req: HyperRequest,
         status_code: StatusCode,
-    ) -> Box<Future<Item = HyperResponse, Error = HttpError>>;
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>>;
+
+    /// Declares the paths this router may accept, as a [`PatternRouter`](struct.PatternRouter.html)
+    /// template (e.g. `/users/:id` or `/static/*path`). The router promises to reject with
+    /// `NotFound` every request whose path doesn't match it.
+    ///
+    /// A [`RouterService`](struct.RouterService.html) using a
+    /// [route index](struct.RouterService.html#method.with_route_index) doesn't call routers
+    /// whose pattern doesn't match the request path. The default implementation declares no
+    /// pattern, and the router is tried for every request.
+    fn route_pattern(&self) -> Option<String> {
+        None
+    }
+
+    /// Declares the methods this router handles.
+    ///
+    /// A [`RouterService`](struct.RouterService.html) only dispatches to a router the requests
+    /// whose method it allows. `HEAD` requests are dispatched as `GET` to routers allowing `GET`
+    /// but not `HEAD`, and the body of the response is dropped. When routers accept the request
+    /// path but none of them allows its method, the request is rejected with
+    /// `MethodNotAllowed`, and the response rendered by the error handler gets an `Allow`
+    /// header. `OPTIONS` requests rejected that way are answered with the `Allow` header.
+    ///
+    /// The default implementation declares no methods, and the router is dispatched requests
+    /// with any method.
+    fn allowed_methods(&self) -> Option<Vec<Method>> {
+        None
+    }
 }
 
 pub trait ErrorHandler: Sync + Send {
-    /// This method processes the request and return the response asynchronously..
+    /// This method processes the request and return the response asynchronously. The context the
+    /// request has been routed with is available in `http_error.context`.
     fn dispatch(&self, http_error: HttpError) -> ResponseFuture;
 }
 
-struct RouteResolver {
-    routers: Rc<Vec<Rc<Router>>>,
+/// Result of [`RouteResolver::resolve`]: the resolver, pointing to the router that accepted the
+/// request if any, the request and the routing result.
+pub(crate) type Resolution = (RouteResolver, HyperRequest, Result<StatusCode, StatusCode>);
+
+/// The methods allowed by the routers that accepted the path of a request, but not its method.
+#[derive(Clone)]
+struct AllowedMethods(Vec<Method>);
+
+impl AllowedMethods {
+    /// Adds `methods` to the methods stored in `ctx`.
+    fn add(ctx: &RouteContext, methods: Vec<Method>) {
+        let mut allowed = ctx.get::<AllowedMethods>()
+            .unwrap_or(AllowedMethods(Vec::new()));
+        for method in methods {
+            if !allowed.0.contains(&method) {
+                allowed.0.push(method);
+            }
+        }
+        ctx.insert(allowed);
+    }
+
+    /// Returns the `Allow` header for the methods stored in `ctx`, if any. `HEAD` is allowed
+    /// along with `GET`, and `OPTIONS` is always allowed.
+    fn header(ctx: &RouteContext) -> Option<Allow> {
+        let AllowedMethods(mut methods) = ctx.get::<AllowedMethods>()?;
+        if methods.contains(&Method::Get) && !methods.contains(&Method::Head) {
+            methods.push(Method::Head);
+        }
+        if !methods.contains(&Method::Options) {
+            methods.push(Method::Options);
+        }
+        Some(Allow(methods))
+    }
+}
+
+/// Returns whether `router` handles requests with `method`.
+fn allows(router: &dyn Router, method: &Method) -> bool {
+    match router.allowed_methods() {
+        Some(methods) => {
+            methods.contains(method) || (*method == Method::Head && methods.contains(&Method::Get))
+        }
+        None => true,
+    }
+}
+
+/// Dispatches `req` to `router`. `HEAD` requests are dispatched as `GET` if the router doesn't
+/// allow `HEAD`, and the body of the response is dropped.
+pub(crate) fn dispatch_to(
+    router: &Rc<dyn Router>,
+    mut req: HyperRequest,
+    status_code: StatusCode,
+    ctx: &RouteContext,
+) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+    let as_get = *req.method() == Method::Head && match router.allowed_methods() {
+        Some(methods) => !methods.contains(&Method::Head),
+        None => false,
+    };
+    if !as_get {
+        return router.dispatch(req, status_code, ctx);
+    }
+    req.set_method(Method::Get);
+    Box::new(
+        router
+            .dispatch(req, status_code, ctx)
+            .map(|res| res.with_body(Body::empty()))
+            .map_err(|mut http_error| {
+                http_error.request.set_method(Method::Head);
+                http_error
+            }),
+    )
+}
+
+/// Copies everything but the body of `req`, with `uri` in place of its URI.
+pub(crate) fn shadow_request(req: &HyperRequest, uri: Uri) -> HyperRequest {
+    let mut shadow = HyperRequest::new(req.method().clone(), uri);
+    shadow.set_version(req.version());
+    *shadow.headers_mut() = req.headers().clone();
+    shadow
+}
+
+/// A panic caught while handling a request, reported as the source of an `HttpError`.
+#[derive(Debug)]
+pub(crate) struct Panic(String);
+
+impl Panic {
+    pub(crate) fn new(payload: &(dyn Any + Send)) -> Panic {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => (*message).to_owned(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => String::from("Box<Any>"),
+            },
+        };
+        Panic(message)
+    }
+}
+
+impl Error for Panic {}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "panicked at '{}'", self.0)
+    }
+}
+
+/// Moves to the next router after `route_resolver` declined `req`. Once every router has been
+/// tried, the request is rejected with `MethodNotAllowed` if some router accepted its path.
+fn skip(
+    route_resolver: RouteResolver,
+    req: HyperRequest,
+    ctx: &RouteContext,
+) -> Loop<Resolution, Resolution> {
+    match route_resolver.next() {
+        Ok(route_resolver) => Loop::Continue((route_resolver, req, Err(StatusCode::NotFound))),
+        Err(route_resolver) => {
+            let status_code = if ctx.contains::<AllowedMethods>() {
+                StatusCode::MethodNotAllowed
+            } else {
+                StatusCode::NotFound
+            };
+            Loop::Break((route_resolver, req, Err(status_code)))
+        }
+    }
+}
+
+pub(crate) struct RouteResolver {
+    routers: Rc<Vec<Rc<dyn Router>>>,
     ix: usize,
 }
 
 impl RouteResolver {
-    fn new(routers: &Rc<Vec<Rc<Router>>>) -> RouteResolver {
+    pub(crate) fn new(routers: &Rc<Vec<Rc<dyn Router>>>) -> RouteResolver {
         RouteResolver {
             routers: Rc::clone(routers),
             ix: 0,
@@ -65,11 +235,12 @@ impl RouteResolver {
     fn route(
         self,
         req: &HyperRequest,
-    ) -> Box<Future<Item = (Self, StatusCode), Error = (Self, StatusCode)>> {
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = (Self, StatusCode), Error = (Self, StatusCode)>> {
         let router = &mut self.get_router();
         match *router {
             Some(ref mut router) => {
-                Box::new(router.route(req).then(|status_code| match status_code {
+                Box::new(router.route(req, ctx).then(|status_code| match status_code {
                     Ok(status_code) => ok((self, status_code)),
                     Err(status_code) => err((self, status_code)),
                 }))
@@ -88,13 +259,59 @@ impl RouteResolver {
         }
     }
 
-    fn get_router(&self) -> Option<Rc<Router>> {
+    pub(crate) fn get_router(&self) -> Option<Rc<dyn Router>> {
         let route = self.routers.get(self.ix);
         match route {
             Some(route) => Some(Rc::clone(route)),
             _ => None,
         }
     }
+
+    /// Tries the routers in turn, until one of them accepts the request and allows its method,
+    /// or fails with a status code other than `NotFound`.
+    pub(crate) fn resolve(
+        self,
+        req: HyperRequest,
+        ctx: RouteContext,
+    ) -> Box<dyn Future<Item = Resolution, Error = Infallible>> {
+        let route_result: Result<StatusCode, StatusCode> = Err(StatusCode::NotFound);
+        Box::new(future::loop_fn(
+            (self, req, route_result),
+            move |(route_resolver, req, _route_result)| {
+                let ctx = ctx.clone();
+                route_resolver
+                    .route(&req, &ctx)
+                    .then(move |route_result| match route_result {
+                        Ok((route_resolver, status_code)) => match route_resolver.get_router() {
+                            Some(ref router) if !allows(&**router, req.method()) => {
+                                let methods = router.allowed_methods().unwrap_or_default();
+                                AllowedMethods::add(&ctx, methods);
+                                Ok(skip(route_resolver, req, &ctx))
+                            }
+                            Some(_) => Ok(Loop::Break((route_resolver, req, Ok(status_code)))),
+                            None => Ok(Loop::Break((
+                                route_resolver,
+                                req,
+                                Err(StatusCode::NotFound),
+                            ))),
+                        },
+                        Err((route_resolver, StatusCode::NotFound)) => {
+                            Ok(skip(route_resolver, req, &ctx))
+                        }
+                        // A mounted group of routers accepting the path but not the method has
+                        // stored its methods: other routers may still accept the method
+                        Err((route_resolver, StatusCode::MethodNotAllowed))
+                            if ctx.contains::<AllowedMethods>() =>
+                        {
+                            Ok(skip(route_resolver, req, &ctx))
+                        }
+                        Err((route_resolver, status_code)) => {
+                            Ok(Loop::Break((route_resolver, req, Err(status_code))))
+                        }
+                    })
+            },
+        ))
+    }
 }
 
 /// A `RouterService` is a service that delegates the computation of an HTTP response to a list of
@@ -102,11 +319,18 @@ impl RouteResolver {
 ///
 /// If no router is suitable for the given HTTP request, then a special `Router`, the `error_handler`,
 /// is used to return the response. `error_handler` is used to render error messages.
+///
+/// A panic in a router or a middleware doesn't take the worker down: it is logged, and answered
+/// with an `InternalServerError` rendered by the `error_handler`.
 pub struct RouterService {
     ///Vector of routers that will participate in the choice of the correct dispatcher
-    routers: Rc<Vec<Rc<Router>>>,
+    routers: Rc<Vec<Rc<dyn Router>>>,
     ///If no router can dispatch the response, error_handler is used to render the error
-    error_handler: Rc<ErrorHandler>,
+    error_handler: Rc<dyn ErrorHandler>,
+    ///Optional index of the routers, by declared pattern
+    index: Option<Rc<RouteIndex>>,
+    ///Middlewares wrapping every request
+    middlewares: Rc<Vec<Rc<dyn Middleware>>>,
 }
 
 impl RouterService {
@@ -114,13 +338,122 @@ impl RouterService {
     ///
     /// - `routers`: Vector of routers that will participate in the choice of the correct dispatcher
     /// - `error_handler`: This special `Router` is invoked when no routes can resolve the request or when a
-    /// `Router` returns an error different from a `NotFound` (404).
-    pub fn new(routers: Vec<Rc<Router>>, error_handler: &Rc<ErrorHandler>) -> RouterService {
+    ///   `Router` returns an error different from a `NotFound` (404).
+    pub fn new(
+        routers: Vec<Rc<dyn Router>>,
+        error_handler: &Rc<dyn ErrorHandler>,
+    ) -> RouterService {
         RouterService {
             routers: Rc::new(routers),
             error_handler: Rc::clone(error_handler),
+            index: None,
+            middlewares: Rc::new(Vec::new()),
         }
     }
+
+    /// Indexes the routers by the pattern they declare with
+    /// [`Router::route_pattern`](trait.Router.html#method.route_pattern).
+    ///
+    /// Instead of trying every router in turn, the service looks the request path up in the
+    /// index and only tries the routers whose pattern matches it, along with the routers that
+    /// don't declare any pattern. Candidates are tried in the original order, so the routing
+    /// result is the same as without index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a router declares an invalid pattern.
+    pub fn with_route_index(mut self) -> RouterService {
+        self.index = Some(Rc::new(RouteIndex::new(&self.routers)));
+        self
+    }
+
+    /// Adds a middleware around routing, dispatching and error handling.
+    ///
+    /// The `before` hooks are called in the order the middlewares have been added, and may
+    /// change the request before it is routed, or reject it with an `HttpError` handled by the
+    /// error handler. The `after` hooks are then called in reverse order on the response,
+    /// whether it comes from a router or from the error handler.
+    pub fn with_middleware(mut self, middleware: Rc<dyn Middleware>) -> RouterService {
+        Rc::make_mut(&mut self.middlewares).push(middleware);
+        self
+    }
+
+    /// Handles `req` with the given context, which may already hold values when the service
+    /// is [mounted](struct.MountRouter.html#method.service) in another one.
+    ///
+    /// Panics of middlewares and routers are turned into `InternalServerError` errors, rendered
+    /// by the error handler. If the error handler or an `after` hook panics, an empty
+    /// `InternalServerError` response is sent. Panics while streaming a body, once the response
+    /// has been sent, are not caught here: bodies streamed from a spawned task (see
+    /// [`range_response`](fn.range_response.html)) log them and end early.
+    pub(crate) fn call_with_context(&self, req: HyperRequest, ctx: RouteContext) -> ResponseFuture {
+        let routers = Rc::clone(&self.routers);
+        let index = self.index.clone();
+        let e_handler = Rc::clone(&self.error_handler);
+        let before_middlewares = Rc::clone(&self.middlewares);
+        let middlewares = Rc::clone(&self.middlewares);
+        let before_ctx = ctx.clone();
+        let route_ctx = ctx.clone();
+        let error_ctx = ctx.clone();
+        // Kept to report panics, as the request is gone by then. The headers are not copied, to
+        // keep the cost off requests which do not panic.
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+        let routed = future::lazy(move || {
+            run_before(&before_middlewares, req, &before_ctx).and_then(move |req| {
+                let route_resolver =
+                    RouteResolver::new(&candidates(&routers, index.as_ref(), &req));
+                route_and_dispatch(route_resolver, req, route_ctx)
+            })
+        });
+        let handled = AssertUnwindSafe(routed)
+            .catch_unwind()
+            .then(move |result| match result {
+                Ok(dispatch_result) => future::result(dispatch_result),
+                Err(payload) => err(HttpError::new(
+                    HyperRequest::new(method, uri),
+                    StatusCode::InternalServerError,
+                ).with_source(Panic::new(&*payload))),
+            })
+            .then(move |dispatch_result| -> ResponseFuture {
+                match dispatch_result {
+                    Ok(res) => Box::new(ok(res)),
+                    Err(mut http_error) => {
+                        if http_error.status_code.is_server_error() {
+                            error!(
+                                "{} {}: {}",
+                                http_error.request.method(),
+                                http_error.request.path(),
+                                http_error
+                            );
+                        }
+                        let headers = http_error.headers.clone();
+                        http_error.context = error_ctx;
+                        Box::new(e_handler.dispatch(http_error).map(move |mut res| {
+                            for header in headers.iter() {
+                                if res.headers().get_raw(header.name()).is_none() {
+                                    let raw = header.raw().clone();
+                                    res.headers_mut().set_raw(header.name().to_owned(), raw);
+                                }
+                            }
+                            res
+                        }))
+                    }
+                }
+            })
+            .map(move |res| run_after(&middlewares, res, &ctx));
+        Box::new(
+            AssertUnwindSafe(handled)
+                .catch_unwind()
+                .then(|result| match result {
+                    Ok(result) => result,
+                    Err(payload) => {
+                        error!("error handling {}", Panic::new(&*payload));
+                        Ok(HyperResponse::new().with_status(StatusCode::InternalServerError))
+                    }
+                }),
+        )
+    }
 }
 
 impl HyperService for RouterService {
@@ -130,74 +463,76 @@ impl HyperService for RouterService {
     type Future = ResponseFuture;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        let route_resolver = RouteResolver::new(&self.routers);
-        let e_handler = Rc::clone(&self.error_handler);
-        let status_code = StatusCode::NotFound;
-        Box::new(
-            future::loop_fn(
-                (route_resolver, req, status_code),
-                |(route_resolver, req, _status_code)| {
-                    route_resolver
-                        .route(&req)
-                        .then(|route_result| match route_result {
-                            Ok((route_resolver, status_code)) => {
-                                let router = route_resolver.get_router();
-                                Ok(Loop::Break((
-                                    route_resolver,
-                                    req,
-                                    match router {
-                                        Some(_) => status_code,
-                                        _ => StatusCode::NotFound,
-                                    },
-                                )))
-                            }
-                            Err((route_resolver, status_code)) => match status_code {
-                                StatusCode::NotFound => match route_resolver.next() {
-                                    Ok(route_resolver) => Ok(Loop::Continue((
-                                        route_resolver,
-                                        req,
-                                        StatusCode::NotFound,
-                                    ))),
-                                    Err(route_resolver) => {
-                                        Ok(Loop::Break((route_resolver, req, StatusCode::NotFound)))
-                                    }
-                                },
-                                _ => Ok(Loop::Break((route_resolver, req, status_code))),
-                            },
-                        })
-                },
-            ).then(
-                |route_resolver_and_req: Result<
-                    (RouteResolver, HyperRequest, StatusCode),
-                    Error,
-                >| match route_resolver_and_req {
-                    Ok((route_resolver, req, status_code)) => {
-                        let router = route_resolver.get_router();
-                        match router {
-            Some(router) => router.dispatch(req, status_code),
-            _ => Box::new(err(HttpError::new(req, StatusCode::NotFound)))//e_handler.dispatch(req, status_code),
-        }
-                    }
-                    Err(e) => panic!("This should never happen!\n{}", e),
-                },
-            )
-                .then(move |dispatch_result| match dispatch_result {
-                    Ok(res) => Box::new(ok(res)),
-                    Err(http_error) => e_handler.dispatch(http_error),
-                }),
-        )
+        self.call_with_context(req, RouteContext::new())
     }
 }
 
+/// Returns the routers that may accept `req`, in order.
+fn candidates(
+    routers: &Rc<Vec<Rc<dyn Router>>>,
+    index: Option<&Rc<RouteIndex>>,
+    req: &HyperRequest,
+) -> Rc<Vec<Rc<dyn Router>>> {
+    match index {
+        Some(index) => Rc::new(
+            index
+                .candidates(req.path())
+                .into_iter()
+                .map(|ix| Rc::clone(&routers[ix]))
+                .collect(),
+        ),
+        None => Rc::clone(routers),
+    }
+}
+
+/// Tries the routers of `route_resolver` in turn, and dispatches the request to the first one
+/// accepting it. `OPTIONS` requests no router allows are answered with the `Allow` header.
+fn route_and_dispatch(
+    route_resolver: RouteResolver,
+    req: HyperRequest,
+    ctx: RouteContext,
+) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+    Box::new(route_resolver.resolve(req, ctx.clone()).then(
+        move |route_resolver_and_req: Result<Resolution, Infallible>| match route_resolver_and_req {
+            Ok((route_resolver, req, Ok(status_code))) => {
+                let router = route_resolver.get_router();
+                match router {
+                    Some(router) => dispatch_to(&router, req, status_code, &ctx),
+                    _ => Box::new(err(HttpError::new(req, StatusCode::NotFound))),
+                }
+            }
+            Ok((_, ref req, Err(StatusCode::MethodNotAllowed)))
+                if *req.method() == Method::Options =>
+            {
+                let mut res = HyperResponse::new().with_header(ContentLength(0));
+                if let Some(allow) = AllowedMethods::header(&ctx) {
+                    res.headers_mut().set(allow);
+                }
+                Box::new(ok(res))
+            }
+            Ok((_, req, Err(StatusCode::MethodNotAllowed))) => {
+                let mut http_error = HttpError::new(req, StatusCode::MethodNotAllowed);
+                if let Some(allow) = AllowedMethods::header(&ctx) {
+                    http_error.headers.set(allow);
+                }
+                Box::new(err(http_error))
+            }
+            Ok((_, req, Err(status_code))) => Box::new(err(HttpError::new(req, status_code))),
+            Err(never) => match never {},
+        },
+    ))
+}
+
 //========================== TESTS =====================================================//
 #[cfg(test)]
 mod tests {
     // extern crate http;
 
     use super::*;
-    use hyper::header::ContentLength;
+    use services::{MountRouter, PatternRouter};
     use hyper::Method;
     use futures::Stream;
+    use std::cell::Cell;
     use std::str::from_utf8;
 
     struct SampleRouter {
@@ -229,8 +564,12 @@ mod tests {
     }
 
     impl Router for SampleRouter {
-        fn route(&self, req: &HyperRequest) -> Box<Future<Item = StatusCode, Error = StatusCode>> {
-            if &self.path == req.path() {
+        fn route(
+            &self,
+            req: &HyperRequest,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+            if self.path == req.path() {
                 Box::new(ok(StatusCode::Ok))
             } else {
                 Box::new(err(StatusCode::NotFound))
@@ -240,7 +579,8 @@ mod tests {
             &self,
             _req: HyperRequest,
             _status_code: StatusCode,
-        ) -> Box<Future<Item = HyperResponse, Error = HttpError>> {
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
             let content = self.content.clone();
             let res = HyperResponse::new()
                 .with_header(ContentLength(content.len() as u64))
@@ -249,15 +589,88 @@ mod tests {
         }
     }
 
-    fn get_routers() -> Vec<Rc<Router>> {
+    /// Router rejecting every request under `/private`.
+    struct ForbiddenRouter;
+
+    impl Router for ForbiddenRouter {
+        fn route(
+            &self,
+            req: &HyperRequest,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+            if req.path().starts_with("/private") {
+                Box::new(err(StatusCode::Forbidden))
+            } else {
+                Box::new(err(StatusCode::NotFound))
+            }
+        }
+        fn dispatch(
+            &self,
+            _req: HyperRequest,
+            _status_code: StatusCode,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+            panic!("dispatch must not be called when routing fails");
+        }
+    }
+
+    #[derive(Clone)]
+    struct Page(String);
+
+    /// Router storing the page requested under `/ctx/` in the context, and failing to dispatch
+    /// the `gone` page.
+    struct ContextRouter;
+
+    impl Router for ContextRouter {
+        fn route(
+            &self,
+            req: &HyperRequest,
+            ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+            if req.path().starts_with("/ctx/") {
+                ctx.insert(Page(req.path()["/ctx/".len()..].to_owned()));
+                Box::new(ok(StatusCode::Ok))
+            } else {
+                Box::new(err(StatusCode::NotFound))
+            }
+        }
+        fn dispatch(
+            &self,
+            req: HyperRequest,
+            _status_code: StatusCode,
+            ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+            match ctx.get::<Page>() {
+                Some(Page(ref page)) if page == "gone" => {
+                    Box::new(err(HttpError::new(req, StatusCode::Gone)))
+                }
+                Some(Page(page)) => Box::new(ok(HyperResponse::new().with_body(page))),
+                None => Box::new(err(HttpError::new(req, StatusCode::InternalServerError))),
+            }
+        }
+    }
+
+    /// Error handler rendering the page found in the context.
+    struct ContextErrorHandler;
+
+    impl ErrorHandler for ContextErrorHandler {
+        fn dispatch(&self, error: HttpError) -> ResponseFuture {
+            let page = error
+                .context
+                .get::<Page>()
+                .map_or_else(String::new, |page| page.0);
+            let content = format!("{} {}", error.status_code.as_u16(), page);
+            Box::new(ok(HyperResponse::new()
+                .with_status(error.status_code)
+                .with_body(content)))
+        }
+    }
+
+    fn get_routers() -> Vec<Rc<dyn Router>> {
         let route1 = Rc::new(SampleRouter::new("/page1", "page1"));
         let route2 = Rc::new(SampleRouter::new("/page2", "page2"));
         let route3 = Rc::new(SampleRouter::new("/page3", "page3"));
-        let mut v_routes: Vec<Rc<Router>> = Vec::new();
-        v_routes.push(route1);
-        v_routes.push(route2);
-        v_routes.push(route3);
-        v_routes
+        vec![route1, route2, route3]
     }
 
     fn dispatch_to_string(response: HyperResponse) -> String {
@@ -278,7 +691,8 @@ mod tests {
             .unwrap();
         let req = HyperRequest::new(Method::Get, uri);
 
-        let route_result = router_resolver.route(&req).wait().ok();
+        let ctx = RouteContext::new();
+        let route_result = router_resolver.route(&req, &ctx).wait().ok();
 
         let (router_resolver, status_code) = match route_result {
             Some((router_resolver, status_code)) => (router_resolver, status_code),
@@ -288,7 +702,7 @@ mod tests {
         assert_eq!(status_code, StatusCode::Ok);
 
         let router = router_resolver.get_router().unwrap();
-        let response = router.dispatch(req, status_code).wait().unwrap();
+        let response = router.dispatch(req, status_code, &ctx).wait().unwrap();
 
         let body = dispatch_to_string(response);
 
@@ -342,7 +756,7 @@ mod tests {
         let req = HyperRequest::new(Method::Get, uri);
 
         let routes = get_routers();
-        let error_handler: Rc<ErrorHandler> = Rc::new(SampleErrorHandler {});
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
 
         let router_service = RouterService::new(routes, &error_handler);
 
@@ -376,7 +790,7 @@ mod tests {
         let req = HyperRequest::new(Method::Get, uri);
 
         let routes = get_routers();
-        let error_handler: Rc<ErrorHandler> = Rc::new(SampleErrorHandler {});
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
 
         let router_service = RouterService::new(routes, &error_handler);
 
@@ -394,4 +808,343 @@ mod tests {
             body
         );
     }
+
+    #[test]
+    fn test_router_service_route_error_goes_to_error_handler() {
+        let uri = "https://www.rss-server.org/private/page1".parse().unwrap();
+        let req = HyperRequest::new(Method::Get, uri);
+
+        let mut routes = get_routers();
+        routes.insert(0, Rc::new(ForbiddenRouter));
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
+
+        let router_service = RouterService::new(routes, &error_handler);
+
+        let response = router_service.call(req).wait().ok().unwrap();
+
+        assert_eq!(response.status(), StatusCode::Forbidden);
+        let body = dispatch_to_string(response);
+        assert_eq!(body, format!("{}", StatusCode::Forbidden.as_u16()));
+    }
+
+    #[test]
+    fn test_router_service_context() {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(ContextErrorHandler {});
+        let mut routes = get_routers();
+        routes.push(Rc::new(ContextRouter));
+        let router_service = RouterService::new(routes, &error_handler);
+
+        let uri = "https://www.rss-server.org/ctx/page4".parse().unwrap();
+        let response = router_service
+            .call(HyperRequest::new(Method::Get, uri))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(dispatch_to_string(response), "page4");
+
+        let uri = "https://www.rss-server.org/ctx/gone".parse().unwrap();
+        let response = router_service
+            .call(HyperRequest::new(Method::Get, uri))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::Gone);
+        assert_eq!(dispatch_to_string(response), "410 gone");
+    }
+
+    fn get_pattern_router(pattern: &str, content: &str) -> Rc<dyn Router> {
+        let content = content.to_owned();
+        Rc::new(PatternRouter::new(pattern, move |_req, params| {
+            let content = match params.get("id") {
+                Some(id) => format!("{} {}", content, id),
+                None => content.clone(),
+            };
+            Box::new(ok(HyperResponse::new().with_body(content)))
+        }))
+    }
+
+    #[test]
+    fn test_router_service_route_index() {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
+        let get_routes = || {
+            let mut routes = vec![
+                get_pattern_router("/users/me", "me"),
+                get_pattern_router("/users/:id", "user"),
+            ];
+            routes.extend(get_routers());
+            routes.push(get_pattern_router("/page1", "shadowed"));
+            routes.push(get_pattern_router("/files/*path", "files"));
+            routes
+        };
+        let linear = RouterService::new(get_routes(), &error_handler);
+        let indexed = RouterService::new(get_routes(), &error_handler).with_route_index();
+
+        for (page, status_code, body) in [
+            ("users/me", StatusCode::Ok, "me"),
+            ("users/42", StatusCode::Ok, "user 42"),
+            ("page1", StatusCode::Ok, "page1"),
+            ("page3", StatusCode::Ok, "page3"),
+            ("files/a/b", StatusCode::Ok, "files"),
+            ("users/42/posts", StatusCode::NotFound, "404"),
+        ] {
+            for router_service in &[&linear, &indexed] {
+                let uri = format!("https://www.rss-server.org/{}", page)
+                    .parse()
+                    .unwrap();
+                let response = router_service
+                    .call(HyperRequest::new(Method::Get, uri))
+                    .wait()
+                    .unwrap();
+                assert_eq!(response.status(), status_code, "{}", page);
+                assert_eq!(dispatch_to_string(response), body, "{}", page);
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct RequestId(usize);
+
+    /// Numbers requests, and rejects the ones to `/private`.
+    struct RequestIdMiddleware {
+        next_id: Cell<usize>,
+    }
+
+    impl Middleware for RequestIdMiddleware {
+        fn before(
+            &self,
+            req: HyperRequest,
+            ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperRequest, Error = HttpError>> {
+            let id = self.next_id.get();
+            self.next_id.set(id + 1);
+            ctx.insert(RequestId(id));
+            if req.path().starts_with("/private") {
+                Box::new(err(HttpError::new(req, StatusCode::Forbidden)))
+            } else {
+                Box::new(ok(req))
+            }
+        }
+
+        fn after(&self, mut res: HyperResponse, ctx: &RouteContext) -> HyperResponse {
+            if let Some(RequestId(id)) = ctx.get::<RequestId>() {
+                res.headers_mut().set_raw("X-Request-Id", id.to_string());
+            }
+            res
+        }
+    }
+
+    #[test]
+    fn test_router_service_middleware() {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
+        let router_service = RouterService::new(get_routers(), &error_handler)
+            .with_middleware(Rc::new(RequestIdMiddleware {
+                next_id: Cell::new(1),
+            }));
+
+        for (id, page, status_code) in [
+            ("1", "page1", StatusCode::Ok),
+            ("2", "private/page1", StatusCode::Forbidden),
+            ("3", "missing", StatusCode::NotFound),
+        ] {
+            let uri = format!("https://www.rss-server.org/{}", page)
+                .parse()
+                .unwrap();
+            let response = router_service
+                .call(HyperRequest::new(Method::Get, uri))
+                .wait()
+                .unwrap();
+            assert_eq!(response.status(), status_code, "{}", page);
+            assert_eq!(
+                response.headers().get_raw("X-Request-Id").unwrap(),
+                id,
+                "{}",
+                page
+            );
+        }
+    }
+
+    #[test]
+    fn test_router_service_methods() {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
+        let show = PatternRouter::new("/users/:id", |_req, params| {
+            let content = format!("show {}", params.get("id").unwrap());
+            Box::new(ok(HyperResponse::new()
+                .with_header(ContentLength(content.len() as u64))
+                .with_body(content)))
+        }).with_methods(vec![Method::Get]);
+        let update = PatternRouter::new("/users/:id", |_req, params| {
+            let content = format!("update {}", params.get("id").unwrap());
+            Box::new(ok(HyperResponse::new().with_body(content)))
+        }).with_methods(vec![Method::Put]);
+        let items = PatternRouter::new("/items", |_req, _params| {
+            Box::new(ok(HyperResponse::new()))
+        }).with_methods(vec![Method::Delete]);
+        let api = MountRouter::new("/api", vec![Rc::new(items)]);
+        let router_service = RouterService::new(
+            vec![Rc::new(show), Rc::new(update), Rc::new(api)],
+            &error_handler,
+        );
+        let call = |method: Method, path: &str| {
+            let uri = format!("https://www.rss-server.org{}", path)
+                .parse()
+                .unwrap();
+            router_service
+                .call(HyperRequest::new(method, uri))
+                .wait()
+                .unwrap()
+        };
+        let users_allow = Allow(vec![Method::Get, Method::Put, Method::Head, Method::Options]);
+
+        let response = call(Method::Get, "/users/42");
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(dispatch_to_string(response), "show 42");
+        let response = call(Method::Put, "/users/42");
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(dispatch_to_string(response), "update 42");
+
+        let response = call(Method::Post, "/users/42");
+        assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers().get::<Allow>(), Some(&users_allow));
+        assert_eq!(dispatch_to_string(response), "405");
+
+        let response = call(Method::Head, "/users/42");
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(
+            response.headers().get::<ContentLength>(),
+            Some(&ContentLength(7))
+        );
+        assert_eq!(dispatch_to_string(response), "");
+
+        let response = call(Method::Options, "/users/42");
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get::<Allow>(), Some(&users_allow));
+        assert_eq!(dispatch_to_string(response), "");
+
+        let response = call(Method::Post, "/missing");
+        assert_eq!(response.status(), StatusCode::NotFound);
+        assert_eq!(response.headers().get::<Allow>(), None);
+
+        // Mounted routers accepting the path but not the method
+        let response = call(Method::Get, "/api/items");
+        assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(
+            response.headers().get::<Allow>(),
+            Some(&Allow(vec![Method::Delete, Method::Options]))
+        );
+        assert_eq!(call(Method::Delete, "/api/items").status(), StatusCode::Ok);
+    }
+
+    /// Rejects every request with `MethodNotAllowed`, without declaring its methods.
+    struct MethodNotAllowedRouter;
+
+    impl Router for MethodNotAllowedRouter {
+        fn route(
+            &self,
+            _req: &HyperRequest,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+            Box::new(err(StatusCode::MethodNotAllowed))
+        }
+
+        fn dispatch(
+            &self,
+            req: HyperRequest,
+            status_code: StatusCode,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+            Box::new(err(HttpError::new(req, status_code)))
+        }
+    }
+
+    #[test]
+    fn test_router_service_method_not_allowed() {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
+        let fallback = PatternRouter::new("/users/:id", |_req, _params| {
+            Box::new(ok(HyperResponse::new()))
+        });
+        let router_service = RouterService::new(
+            vec![Rc::new(MethodNotAllowedRouter), Rc::new(fallback)],
+            &error_handler,
+        );
+        let uri = "https://www.rss-server.org/users/42".parse().unwrap();
+        let response = router_service
+            .call(HyperRequest::new(Method::Get, uri))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers().get::<Allow>(), None);
+    }
+
+    /// Panics while routing, while dispatching or while polling the response, depending on the
+    /// path.
+    struct PanicRouter;
+
+    impl Router for PanicRouter {
+        fn route(
+            &self,
+            req: &HyperRequest,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+            if req.path() == "/route" {
+                panic!("route failed");
+            }
+            Box::new(ok(StatusCode::Ok))
+        }
+
+        fn dispatch(
+            &self,
+            req: HyperRequest,
+            _status_code: StatusCode,
+            _ctx: &RouteContext,
+        ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+            match req.path() {
+                "/dispatch" => panic!("dispatch failed for {}", req.path()),
+                _ => Box::new(future::lazy(|| -> future::FutureResult<HyperResponse, HttpError> {
+                    panic!("poll failed")
+                })),
+            }
+        }
+    }
+
+    struct PanicErrorHandler;
+
+    impl ErrorHandler for PanicErrorHandler {
+        fn dispatch(&self, _error: HttpError) -> ResponseFuture {
+            panic!("error handler failed");
+        }
+    }
+
+    #[test]
+    fn test_router_service_panics() {
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
+        let router_service = RouterService::new(vec![Rc::new(PanicRouter)], &error_handler);
+        for path in ["/route", "/dispatch", "/poll"] {
+            let uri = format!("https://www.rss-server.org{}", path).parse().unwrap();
+            let response = router_service
+                .call(HyperRequest::new(Method::Get, uri))
+                .wait()
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::InternalServerError, "{}", path);
+            assert_eq!(dispatch_to_string(response), "500", "{}", path);
+        }
+
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(PanicErrorHandler);
+        let router_service = RouterService::new(vec![Rc::new(PanicRouter)], &error_handler);
+        let uri = "https://www.rss-server.org/route".parse().unwrap();
+        let response = router_service
+            .call(HyperRequest::new(Method::Get, uri))
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+        assert_eq!(dispatch_to_string(response), "");
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let payload: Box<dyn Any + Send> = Box::new("static message");
+        assert_eq!(Panic::new(&*payload).to_string(), "panicked at 'static message'");
+        let payload: Box<dyn Any + Send> = Box::new(String::from("formatted 42"));
+        assert_eq!(Panic::new(&*payload).to_string(), "panicked at 'formatted 42'");
+        let payload: Box<dyn Any + Send> = Box::new(42);
+        assert_eq!(Panic::new(&*payload).to_string(), "panicked at 'Box<Any>'");
+    }
 }
@@ -1,37 +1,1412 @@
+use HttpError;
+use services::{OriginalUri, RouteContext, Router};
+use services::autoindex::{read_listing, render_listing, ListingFormat, ListingQuery};
+use services::range::{range_response, ChunkStream, RangeSource};
+
+use futures::{Async, Poll, Stream};
+use futures::future::{err, ok, result, Future};
+use hyper::{Chunk, Method, StatusCode};
+use hyper::Error as HyperError;
+use hyper::header::{q, AcceptEncoding, ContentEncoding, ContentLength, ContentType, ETag,
+                    Encoding, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, LastModified,
+                    Location, Quality};
+use hyper::server::{Request as HyperRequest, Response as HyperResponse};
+use mime_guess;
+use percent_encoding::percent_decode;
 use tokio_core::reactor::Handle;
-use std::path::PathBuf;
-//use hyper_staticfile::Static;
-//
-//use hyper::server::Request;
-//use hyper::server::Response;
-//use rss_engine::*;
 
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size of the chunks used to stream files.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of content hashes a `StaticRouterService` keeps.
+const MAX_CACHED_HASHES: usize = 1024;
+
+/// How a [`StaticRouterService`](struct.StaticRouterService.html) treats symbolic links found
+/// under its root.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Symbolic links are followed wherever they point.
+    Follow,
+    /// Requests whose path goes through a symbolic link are rejected with `Forbidden`.
+    Deny,
+    /// Symbolic links are followed only when their target stays inside the root. This is the
+    /// default policy.
+    FollowWithinRoot,
+}
+
+/// How a [`StaticRouterService`](struct.StaticRouterService.html) computes the `ETag` of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ETagStrategy {
+    /// No `ETag` is sent.
+    Disabled,
+    /// Strong `ETag` built from the file modification time and size. This is the default
+    /// strategy.
+    Metadata,
+    /// Weak `ETag` built from the file modification time and size.
+    WeakMetadata,
+    /// Strong `ETag` built from a hash of the file content. Hashes are cached until the file
+    /// modification time or size changes, for up to 1024 files.
+    ///
+    /// A file is hashed on the reactor thread the first time it is requested, which stalls the
+    /// other connections of the worker: keep this strategy for small files.
+    ContentHash,
+}
+
+/// The `[static]` section of `http-server.toml`, read by
+/// [`StaticRouterService::from_config`](struct.StaticRouterService.html#method.from_config).
+///
+/// ```toml
+/// [static]
+/// root = "public"
+/// index_files = ["index.html"]
+/// autoindex = false
+/// autoindex_hidden = false
+/// precompressed = true
+/// etag = "metadata"                  # disabled, metadata, weak_metadata or content_hash
+/// symlinks = "follow_within_root"    # follow, deny or follow_within_root
+/// ```
+///
+/// Only `root` is required, the other fields have the defaults of
+/// [`StaticRouterService::new`](struct.StaticRouterService.html#method.new).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StaticConfig {
+    /// Directory of the files, relative to the configuration directory
+    pub root: PathBuf,
+    #[serde(default)]
+    pub index_files: Vec<String>,
+    #[serde(default)]
+    pub autoindex: bool,
+    #[serde(default)]
+    pub autoindex_hidden: bool,
+    #[serde(default = "default_precompressed")]
+    pub precompressed: bool,
+    #[serde(default = "default_etag")]
+    pub etag: ETagStrategy,
+    #[serde(default = "default_symlinks")]
+    pub symlinks: SymlinkPolicy,
+}
+
+fn default_precompressed() -> bool {
+    true
+}
+
+fn default_etag() -> ETagStrategy {
+    ETagStrategy::Metadata
+}
+
+fn default_symlinks() -> SymlinkPolicy {
+    SymlinkPolicy::FollowWithinRoot
+}
+
+/// What a request path maps to under the root of a `StaticRouterService`.
+enum Target {
+    File(PathBuf, Metadata),
+    /// A directory without index file, to be listed
+    Directory(PathBuf),
+    /// A directory requested without trailing slash
+    AddSlash,
+}
+
+/// A representation of a file selected for a request: the file itself, or one of its
+/// precompressed sidecars.
+#[derive(Clone)]
+struct Representation {
+    path: PathBuf,
+    metadata: Metadata,
+    encoding: Option<Encoding>,
+    /// Whether the file has sidecars, so that the response depends on `Accept-Encoding`
+    varies: bool,
+}
+
+/// What `route` found for a request path, kept in the `RouteContext` so that `dispatch` doesn't
+/// look for the file, select its representation and compute its entity tag again.
+#[derive(Clone)]
+struct Routed {
+    /// Path of the request the target has been found for
+    req_path: String,
+    target: RoutedTarget,
+}
+
+#[derive(Clone)]
+enum RoutedTarget {
+    /// A file, with the `Content-Type` of the file, the representation to send and its `ETag`
+    File(ContentType, Box<Representation>, Option<EntityTag>),
+    /// A directory without index file, to be listed
+    Directory(PathBuf),
+    /// A directory requested without trailing slash
+    AddSlash,
+}
+
+/// A `StaticRouterService` is a [`Router`](trait.Router.html) that serves files found under a
+/// `root` directory.
+///
+/// Requests that do not map to a regular file under `root` are rejected with `NotFound`, so the
+/// next router of the [`RouterService`](struct.RouterService.html) gets a chance to handle them.
+/// Directories are served only when [index files](#method.with_index_files) or
+/// [autoindex](#method.with_autoindex) are enabled: the first index file found in the directory
+/// is served, otherwise its entries are listed. Directories requested without trailing slash
+/// are redirected with `MovedPermanently`, so relative links keep working.
+/// Request paths that can't name a file under `root` (`..` segments, encoded separators or NUL
+/// bytes) are rejected with `NotFound` as well, while paths going through symbolic links
+/// forbidden by the [`SymlinkPolicy`](enum.SymlinkPolicy.html) are rejected with `Forbidden`, and
+/// the response is rendered by the `ErrorHandler`.
+///
+/// When [precompressed](#method.with_precompressed) sidecars such as `app.js.br` or `app.js.gz`
+/// exist next to a file, and the `Accept-Encoding` header of the request allows them, the
+/// sidecar is served in place of the file with the matching `Content-Encoding`.
+///
+/// Files are streamed back in chunks, with `Content-Type` guessed from the file extension,
+/// `Content-Length`, `Last-Modified` and `ETag` (see [`ETagStrategy`](enum.ETagStrategy.html)).
+/// `GET` and `HEAD` requests whose `If-None-Match` or `If-Modified-Since` header matches the
+/// file are routed with `NotModified`, and answered with an empty `304` response. `Range`
+/// requests are answered through [`range_response`](fn.range_response.html). Only `GET` and
+/// `HEAD` requests are allowed.
 pub struct StaticRouterService {
     root: PathBuf,
+    /// `root` with symbolic links resolved, to check the targets of links under it
+    canonical_root: PathBuf,
+    handle: Handle,
+    symlink_policy: SymlinkPolicy,
+    etag_strategy: ETagStrategy,
+    index_files: Vec<String>,
+    autoindex: bool,
+    autoindex_hidden: bool,
+    precompressed: bool,
+    /// Content hashes, with the modification time and size they were computed for
+    hashes: RefCell<HashMap<PathBuf, CachedHash>>,
+    /// Incremented on every hash lookup, to find the least recently used hash
+    hash_clock: Cell<u64>,
 }
 
+/// The content hash of a file, valid as long as its modification time and size don't change.
+struct CachedHash {
+    modified: SystemTime,
+    len: u64,
+    hash: u64,
+    last_used: u64,
+}
 
 impl StaticRouterService {
+    /// Creates a router serving the content of `path`. File contents are streamed on the reactor
+    /// of `handle`, with blocking reads of at most 64 KiB at a time.
     pub fn new(handle: &Handle, path: PathBuf) -> StaticRouterService {
-        StaticRouterService { root: path }
-    }
-
-    //    fn route(&self, req: Request) -> ResponseFuture {
-    //        let stat_file =
-    //            self.static_.call(req)
-    //                .and_then(|res| {
-    //                    let statusCode = res.status();
-    //                    let statusRaw: u16 = statusCode.into();
-    //
-    //                    if statusRaw >= 400 {
-    //                        future::ok(Response::new()
-    //                            .with_status(statusCode)
-    //                            .with_header(ContentLength(HTML_ERROR.len() as u64))
-    //                            .with_body(HTML_ERROR))
-    //                    } else {
-    //                        future::ok(res)
-    //                    }
-    //                });
-    //        Box::new(stat_file)
-    //    }
+        let canonical_root = path.canonicalize().unwrap_or_else(|e| {
+            warn!("could not resolve static root {:?}: {}", path, e);
+            path.clone()
+        });
+        StaticRouterService {
+            root: path,
+            canonical_root,
+            handle: handle.clone(),
+            symlink_policy: SymlinkPolicy::FollowWithinRoot,
+            etag_strategy: ETagStrategy::Metadata,
+            index_files: Vec::new(),
+            autoindex: false,
+            autoindex_hidden: false,
+            precompressed: true,
+            hashes: RefCell::new(HashMap::new()),
+            hash_clock: Cell::new(0),
+        }
+    }
+
+    /// Creates a router configured by `config`, whose relative `root` is resolved against
+    /// `config_path`, usually the directory of `http-server.toml` given by
+    /// [`ConfigHandle::config_path`](struct.ConfigHandle.html#method.config_path).
+    pub fn from_config(
+        handle: &Handle,
+        config_path: &Path,
+        config: &StaticConfig,
+    ) -> StaticRouterService {
+        StaticRouterService::new(handle, config_path.join(&config.root))
+            .with_index_files(config.index_files.clone())
+            .with_autoindex(config.autoindex)
+            .with_autoindex_hidden(config.autoindex_hidden)
+            .with_precompressed(config.precompressed)
+            .with_etag_strategy(config.etag)
+            .with_symlink_policy(config.symlinks)
+    }
+
+    /// Sets how the `ETag` of served files is computed.
+    pub fn with_etag_strategy(mut self, etag_strategy: ETagStrategy) -> StaticRouterService {
+        self.etag_strategy = etag_strategy;
+        self
+    }
+
+    /// Sets the file names (e.g. `index.html`) looked up, in order, when a directory is
+    /// requested.
+    pub fn with_index_files(mut self, index_files: Vec<String>) -> StaticRouterService {
+        self.index_files = index_files;
+        self
+    }
+
+    /// Enables listing the entries of requested directories that have no index file.
+    ///
+    /// Listings are rendered as HTML, or as JSON when asked for with `?format=json` or an
+    /// `Accept` header preferring `application/json`. Entries are sorted by name, or by
+    /// `?sort=size` or `?sort=mtime`, in the order given by `?order=asc|desc`.
+    pub fn with_autoindex(mut self, autoindex: bool) -> StaticRouterService {
+        self.autoindex = autoindex;
+        self
+    }
+
+    /// Lists hidden entries, whose name starts with a dot like `.git` or `.env`. They are left
+    /// out of listings by default.
+    pub fn with_autoindex_hidden(mut self, autoindex_hidden: bool) -> StaticRouterService {
+        self.autoindex_hidden = autoindex_hidden;
+        self
+    }
+
+    /// Enables serving precompressed `.br` and `.gz` sidecars of files. Sidecars are served by
+    /// default; responses for files that have sidecars carry `Vary: Accept-Encoding`.
+    pub fn with_precompressed(mut self, precompressed: bool) -> StaticRouterService {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// Sets the policy applied to symbolic links found under `root`.
+    pub fn with_symlink_policy(mut self, symlink_policy: SymlinkPolicy) -> StaticRouterService {
+        self.symlink_policy = symlink_policy;
+        self
+    }
+
+    /// Maps the request path to a path under `root`.
+    ///
+    /// Fails with `Forbidden` when the path goes through a symbolic link not allowed by the
+    /// symlink policy, and with `NotFound` when it tries to leave `root` or can't be mapped to a
+    /// file name.
+    fn resolve(&self, req_path: &str) -> Result<PathBuf, StatusCode> {
+        let mut path = self.root.clone();
+        for segment in req_path.split('/') {
+            let segment = percent_decode(segment.as_bytes())
+                .decode_utf8()
+                .map_err(|_| StatusCode::NotFound)?;
+            match segment.as_ref() {
+                "" | "." => continue,
+                ".." => return Err(StatusCode::NotFound),
+                segment if !is_plain_segment(segment) => return Err(StatusCode::NotFound),
+                segment => path.push(segment),
+            }
+            if self.symlink_policy == SymlinkPolicy::Deny && is_symlink(&path) {
+                return Err(StatusCode::Forbidden);
+            }
+        }
+
+        if self.symlink_policy == SymlinkPolicy::FollowWithinRoot {
+            let target = path.canonicalize().map_err(|_| StatusCode::NotFound)?;
+            if !target.starts_with(&self.canonical_root) {
+                return Err(StatusCode::Forbidden);
+            }
+        }
+        Ok(path)
+    }
+
+    /// Returns the regular file the request path maps to, along with its metadata.
+    fn find_file(&self, req_path: &str) -> Result<(PathBuf, Metadata), StatusCode> {
+        let path = self.resolve(req_path)?;
+        match path.metadata() {
+            Ok(ref metadata) if metadata.is_file() => Ok((path, metadata.clone())),
+            _ => Err(StatusCode::NotFound),
+        }
+    }
+
+    /// Returns what the request path maps to, taking index files and autoindex into account.
+    fn find_target(&self, req_path: &str) -> Result<Target, StatusCode> {
+        let path = self.resolve(req_path)?;
+        let metadata = path.metadata().map_err(|_| StatusCode::NotFound)?;
+        if metadata.is_file() {
+            return Ok(Target::File(path, metadata));
+        }
+        if !metadata.is_dir() || (self.index_files.is_empty() && !self.autoindex) {
+            return Err(StatusCode::NotFound);
+        }
+        if !req_path.ends_with('/') {
+            return Ok(Target::AddSlash);
+        }
+        for index_file in &self.index_files {
+            if let Ok((path, metadata)) = self.find_file(&format!("{}{}", req_path, index_file)) {
+                return Ok(Target::File(path, metadata));
+            }
+        }
+        if self.autoindex {
+            Ok(Target::Directory(path))
+        } else {
+            Err(StatusCode::NotFound)
+        }
+    }
+
+    /// Returns what the request maps to, with the representation and entity tag of files.
+    fn route_target(&self, req: &HyperRequest) -> Result<RoutedTarget, StatusCode> {
+        match self.find_target(req.path())? {
+            Target::File(path, metadata) => {
+                let mime = mime_guess::from_path(&path).first_or_octet_stream();
+                let selected = self.select_representation(req, path, metadata);
+                let etag = self.entity_tag(&selected.path, &selected.metadata);
+                Ok(RoutedTarget::File(ContentType(mime), Box::new(selected), etag))
+            }
+            Target::Directory(dir) => Ok(RoutedTarget::Directory(dir)),
+            Target::AddSlash => Ok(RoutedTarget::AddSlash),
+        }
+    }
+
+    /// Returns `true` if the symlink policy lets `path`, found in a directory under `root`, be
+    /// served.
+    fn is_within_policy(&self, path: &Path) -> bool {
+        match self.symlink_policy {
+            SymlinkPolicy::Follow => true,
+            SymlinkPolicy::Deny => !is_symlink(path),
+            SymlinkPolicy::FollowWithinRoot => match path.canonicalize() {
+                Ok(target) => target.starts_with(&self.canonical_root),
+                Err(_) => false,
+            },
+        }
+    }
+
+    /// Picks the precompressed sidecar of a file preferred by the request, if any. Brotli wins
+    /// over gzip unless the client gives gzip a higher quality.
+    fn select_representation(
+        &self,
+        req: &HyperRequest,
+        path: PathBuf,
+        metadata: Metadata,
+    ) -> Representation {
+        let mut selected = Representation {
+            path,
+            metadata,
+            encoding: None,
+            varies: false,
+        };
+        if !self.precompressed {
+            return selected;
+        }
+        let mut sidecar_base = OsString::from(selected.path.as_os_str());
+        sidecar_base.push(".");
+        let mut selected_quality = None;
+        for &(ref encoding, extension) in &[(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")] {
+            let mut sidecar = sidecar_base.clone();
+            sidecar.push(extension);
+            let sidecar = PathBuf::from(sidecar);
+            let sidecar_metadata = match sidecar.metadata() {
+                Ok(metadata) if metadata.is_file() && self.is_within_policy(&sidecar) => metadata,
+                _ => continue,
+            };
+            selected.varies = true;
+            let quality = match accepted_quality(req, encoding) {
+                Some(quality) => quality,
+                None => continue,
+            };
+            // None compares lower than any quality
+            if Some(quality) > selected_quality {
+                selected_quality = Some(quality);
+                selected.path = sidecar;
+                selected.metadata = sidecar_metadata;
+                selected.encoding = Some(encoding.clone());
+            }
+        }
+        selected
+    }
+
+    /// Answers a request for a directory with the listing of its entries.
+    #[allow(clippy::result_large_err)]
+    fn list_directory(&self, req: HyperRequest, dir: &Path) -> Result<HyperResponse, HttpError> {
+        let query = ListingQuery::from_request(&req);
+        let include = |path: &Path| self.is_within_policy(path);
+        let entries = match read_listing(dir, &query, self.autoindex_hidden, include) {
+            Ok(entries) => entries,
+            Err(e) => {
+                return Err(HttpError::new(req, StatusCode::InternalServerError)
+                    .with_source(path_error("could not list", dir, &e)))
+            }
+        };
+        let body = render_listing(req.path(), &entries, query.format);
+        let mut res = HyperResponse::new()
+            .with_header(match query.format {
+                ListingFormat::Html => ContentType::html(),
+                ListingFormat::Json => ContentType::json(),
+            })
+            .with_header(ContentLength(body.len() as u64))
+            .with_body(body);
+        res.headers_mut().set_raw("Vary", "Accept");
+        Ok(res)
+    }
+
+    /// Computes the `ETag` of a file according to the configured strategy.
+    fn entity_tag(&self, path: &Path, metadata: &Metadata) -> Option<EntityTag> {
+        match self.etag_strategy {
+            ETagStrategy::Disabled => None,
+            ETagStrategy::Metadata => metadata_tag(metadata).map(EntityTag::strong),
+            ETagStrategy::WeakMetadata => metadata_tag(metadata).map(EntityTag::weak),
+            ETagStrategy::ContentHash => self.content_hash(path, metadata)
+                .map(|hash| EntityTag::strong(format!("{:016x}", hash))),
+        }
+    }
+
+    fn content_hash(&self, path: &Path, metadata: &Metadata) -> Option<u64> {
+        let modified = metadata.modified().ok()?;
+        let now = self.hash_clock.get() + 1;
+        self.hash_clock.set(now);
+        if let Some(cached) = self.hashes.borrow_mut().get_mut(path) {
+            if cached.modified == modified && cached.len == metadata.len() {
+                cached.last_used = now;
+                return Some(cached.hash);
+            }
+        }
+        match hash_file(path) {
+            Ok(hash) => {
+                let mut hashes = self.hashes.borrow_mut();
+                if !hashes.contains_key(path) && hashes.len() >= MAX_CACHED_HASHES {
+                    evict_hashes(&mut hashes);
+                }
+                hashes.insert(
+                    path.to_path_buf(),
+                    CachedHash {
+                        modified,
+                        len: metadata.len(),
+                        hash,
+                        last_used: now,
+                    },
+                );
+                Some(hash)
+            }
+            Err(e) => {
+                warn!("could not hash {:?}: {}", path, e);
+                self.hashes.borrow_mut().remove(path);
+                None
+            }
+        }
+    }
+}
+
+/// Builds the location of a directory requested without trailing slash. Leading slashes are
+/// collapsed, as `//host/dir/` would be taken for another host.
+fn slash_location(path: &str, query: Option<&str>) -> String {
+    let path = path.trim_start_matches('/');
+    match query {
+        Some(query) => format!("/{}/?{}", path, query),
+        None => format!("/{}/", path),
+    }
+}
+
+/// Adds `path` to `e`, for the logs: the path of a file is not shown to the client.
+fn path_error(action: &str, path: &Path, e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{} {:?}: {}", action, path, e))
+}
+
+/// Builds an entity tag value from the modification time and size of a file.
+fn metadata_tag(metadata: &Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "{:x}.{:x}-{:x}",
+        modified.as_secs(),
+        modified.subsec_nanos(),
+        metadata.len()
+    ))
+}
+
+/// Makes room in a full hash cache: drops the hashes of files that changed or disappeared, or
+/// the least recently used one if every hash is still valid.
+fn evict_hashes(hashes: &mut HashMap<PathBuf, CachedHash>) {
+    hashes.retain(|path, cached| match path.metadata() {
+        Ok(metadata) => {
+            metadata.modified().ok() == Some(cached.modified) && metadata.len() == cached.len
+        }
+        Err(_) => false,
+    });
+    if hashes.len() < MAX_CACHED_HASHES {
+        return;
+    }
+    let least_recent = hashes
+        .iter()
+        .min_by_key(|&(_, cached)| cached.last_used)
+        .map(|(path, _)| path.clone());
+    if let Some(path) = least_recent {
+        hashes.remove(&path);
+    }
+}
+
+/// Hashes the content of a file with 64 bit FNV-1a, which is stable across builds and platforms.
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for byte in &buf[..n] {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// Evaluates `If-None-Match` and `If-Modified-Since` against the validators of a file, following
+/// RFC 7232: `If-Modified-Since` is ignored when `If-None-Match` is present.
+fn is_not_modified(
+    req: &HyperRequest,
+    etag: Option<&EntityTag>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    match *req.method() {
+        Method::Get | Method::Head => (),
+        _ => return false,
+    }
+    if let Some(if_none_match) = req.headers().get::<IfNoneMatch>() {
+        return match *if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(ref tags) => match etag {
+                Some(etag) => tags.iter().any(|tag| tag.weak_eq(etag)),
+                None => false,
+            },
+        };
+    }
+    match (req.headers().get::<IfModifiedSince>(), last_modified) {
+        // Last-Modified has a one second resolution, so compare the truncated value
+        (Some(&IfModifiedSince(since)), Some(modified)) => {
+            SystemTime::from(HttpDate::from(modified)) <= SystemTime::from(since)
+        }
+        _ => false,
+    }
+}
+
+/// Returns the quality the `Accept-Encoding` header of a request gives to `encoding`, or `None`
+/// if the encoding is not acceptable.
+fn accepted_quality(req: &HyperRequest, encoding: &Encoding) -> Option<Quality> {
+    let accept_encoding = req.headers().get::<AcceptEncoding>()?;
+    let any = Encoding::EncodingExt(String::from("*"));
+    let quality = accept_encoding
+        .iter()
+        .find(|item| item.item == *encoding)
+        .or_else(|| accept_encoding.iter().find(|item| item.item == any))
+        .map(|item| item.quality)?;
+    if quality == q(0) {
+        None
+    } else {
+        Some(quality)
+    }
+}
+
+/// Returns `true` if a decoded path segment is a single, plain file name.
+fn is_plain_segment(segment: &str) -> bool {
+    if segment.contains('\0') || segment.contains('/') || segment.contains('\\') {
+        return false;
+    }
+    let mut components = Path::new(segment).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata()
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+impl Router for StaticRouterService {
+    fn route(
+        &self,
+        req: &HyperRequest,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+        let target = match self.route_target(req) {
+            Ok(target) => target,
+            Err(status_code) => return Box::new(err(status_code)),
+        };
+        let status_code = match target {
+            RoutedTarget::AddSlash => StatusCode::MovedPermanently,
+            RoutedTarget::Directory(_) => StatusCode::Ok,
+            RoutedTarget::File(_, ref selected, ref etag) => {
+                let modified = selected.metadata.modified().ok();
+                if is_not_modified(req, etag.as_ref(), modified) {
+                    StatusCode::NotModified
+                } else {
+                    StatusCode::Ok
+                }
+            }
+        };
+        ctx.insert(Routed {
+            req_path: req.path().to_owned(),
+            target,
+        });
+        Box::new(ok(status_code))
+    }
+
+    fn dispatch(
+        &self,
+        req: HyperRequest,
+        status_code: StatusCode,
+        ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+        match status_code {
+            StatusCode::Ok | StatusCode::NotModified | StatusCode::MovedPermanently => (),
+            _ => return Box::new(err(HttpError::new(req, status_code))),
+        }
+        // Reuse what `route` found, unless the request has been routed by another router
+        let target = match ctx.get::<Routed>() {
+            Some(routed) if routed.req_path == req.path() => Ok(routed.target),
+            _ => self.route_target(&req),
+        };
+        let (content_type, selected, etag) = match target {
+            Ok(RoutedTarget::File(content_type, selected, etag)) => (content_type, selected, etag),
+            Ok(RoutedTarget::Directory(dir)) => {
+                return Box::new(result(self.list_directory(req, &dir)))
+            }
+            Ok(RoutedTarget::AddSlash) => {
+                let location = match ctx.get::<OriginalUri>() {
+                    Some(OriginalUri(uri)) => slash_location(uri.path(), uri.query()),
+                    None => slash_location(req.path(), req.query()),
+                };
+                return Box::new(ok(HyperResponse::new()
+                    .with_status(StatusCode::MovedPermanently)
+                    .with_header(Location::new(location))));
+            }
+            Err(status_code) => return Box::new(err(HttpError::new(req, status_code))),
+        };
+        let Representation {
+            path,
+            metadata,
+            encoding,
+            varies,
+        } = *selected;
+
+        let mut res = HyperResponse::new();
+        if varies {
+            res.headers_mut().set_raw("Vary", "Accept-Encoding");
+        }
+        if let Some(etag) = etag {
+            res.headers_mut().set(ETag(etag));
+        }
+        if let Ok(modified) = metadata.modified() {
+            res.headers_mut().set(LastModified(HttpDate::from(modified)));
+        }
+        if status_code == StatusCode::NotModified {
+            return Box::new(ok(res.with_status(StatusCode::NotModified)));
+        }
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                return Box::new(err(HttpError::new(req, StatusCode::InternalServerError)
+                    .with_source(path_error("could not open", &path, &e))))
+            }
+        };
+        let mut res = res.with_header(content_type);
+        if let Some(encoding) = encoding {
+            res.headers_mut().set(ContentEncoding(vec![encoding]));
+        }
+        Box::new(result(range_response(
+            req,
+            res,
+            metadata.len(),
+            FileSource { file },
+            &self.handle,
+        )))
+    }
+
+    fn allowed_methods(&self) -> Option<Vec<Method>> {
+        Some(vec![Method::Get])
+    }
+}
+
+/// [`RangeSource`] reading from an open file.
+struct FileSource {
+    file: File,
+}
+
+impl RangeSource for FileSource {
+    fn stream_range(&self, first: u64, last: u64) -> ChunkStream {
+        match self.file.try_clone() {
+            Ok(file) => Box::new(FileChunkStream {
+                file,
+                position: first,
+                remaining: last - first + 1,
+            }),
+            Err(e) => Box::new(::futures::stream::once(Err(HyperError::Io(e)))),
+        }
+    }
+}
+
+/// Stream reading `remaining` bytes of a file from `position`, in chunks of at most
+/// [`CHUNK_SIZE`] bytes.
+///
+/// The file handle may be shared with other streams over the same file, so every read seeks
+/// to `position` first.
+///
+/// Reads are blocking and run on the reactor thread: local files are usually read fast enough
+/// that handing them to a thread pool costs more than it saves. Each poll reads a single chunk,
+/// so the reactor is never blocked for more than one read of [`CHUNK_SIZE`] bytes, and the body
+/// channel stops polling the stream while the client is not reading.
+struct FileChunkStream {
+    file: File,
+    position: u64,
+    remaining: u64,
+}
+
+impl Stream for FileChunkStream {
+    type Item = Chunk;
+    type Error = HyperError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.remaining == 0 {
+            return Ok(Async::Ready(None));
+        }
+        // Compared as u64, as `remaining` may not fit in a usize
+        let mut buf = vec![0; cmp::min(CHUNK_SIZE as u64, self.remaining) as usize];
+        self.file.seek(SeekFrom::Start(self.position))?;
+        match self.file.read(&mut buf)? {
+            0 => Err(HyperError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file truncated while streaming",
+            ))),
+            n => {
+                buf.truncate(n);
+                self.position += n as u64;
+                self.remaining -= n as u64;
+                Ok(Async::Ready(Some(Chunk::from(buf))))
+            }
+        }
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{ContentRange, ContentRangeSpec, Range};
+    use services::MountRouter;
+    use std::rc::Rc;
+    use tokio_core::reactor::Core;
+    use std::env;
+    use std::fs::{create_dir_all, remove_file};
+    use std::io::Write;
+
+    fn get_root(name: &str) -> PathBuf {
+        let root: PathBuf = [
+            env::var("CARGO_MANIFEST_DIR").unwrap().as_str(),
+            "tests",
+            "out",
+            "static",
+            name,
+        ].iter()
+            .collect();
+        create_dir_all(root.join("sub")).unwrap();
+        File::create(root.join("index.html"))
+            .unwrap()
+            .write_all(b"<h1>index</h1>")
+            .unwrap();
+        File::create(root.join("sub").join("data.json"))
+            .unwrap()
+            .write_all(b"{\"a\": 1}")
+            .unwrap();
+        root
+    }
+
+    fn get_request(path: &str) -> HyperRequest {
+        let uri = format!("https://www.rss-server.org{}", path).parse().unwrap();
+        HyperRequest::new(Method::Get, uri)
+    }
+
+    fn route(router: &StaticRouterService, req: &HyperRequest) -> Result<StatusCode, StatusCode> {
+        router.route(req, &RouteContext::new()).wait()
+    }
+
+    fn dispatch(
+        router: &StaticRouterService,
+        req: HyperRequest,
+        status_code: StatusCode,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+        router.dispatch(req, status_code, &RouteContext::new())
+    }
+
+    #[test]
+    fn test_route_existing_files() {
+        let core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("route"));
+
+        for path in &["/index.html", "/sub/data.json", "/./sub//data.json"] {
+            let status_code = route(&router, &get_request(path));
+            assert_eq!(status_code, Ok(StatusCode::Ok), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_route_not_found() {
+        let core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("not_found"));
+
+        for path in &["/missing.html", "/sub", "/", "/sub/../index.html"] {
+            let status_code = route(&router, &get_request(path));
+            assert_eq!(status_code, Err(StatusCode::NotFound), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_dispatch_index_file() {
+        let mut core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("index_file"))
+            .with_index_files(vec![String::from("missing.html"), String::from("index.html")]);
+
+        assert_eq!(route(&router, &get_request("/")), Ok(StatusCode::Ok));
+        assert_eq!(
+            route(&router, &get_request("/sub/")),
+            Err(StatusCode::NotFound)
+        );
+        let response = core.run(dispatch(&router, get_request("/"), StatusCode::Ok))
+            .unwrap();
+        let body = core.run(response.body().concat2()).unwrap();
+        assert_eq!(&body[..], b"<h1>index</h1>");
+    }
+
+    #[test]
+    fn test_dispatch_directory_redirect() {
+        let mut core = Core::new().unwrap();
+        let router =
+            StaticRouterService::new(&core.handle(), get_root("redirect")).with_autoindex(true);
+
+        let req = get_request("/sub?sort=size");
+        assert_eq!(route(&router, &req), Ok(StatusCode::MovedPermanently));
+        let response = core.run(dispatch(&router, req, StatusCode::MovedPermanently))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::MovedPermanently);
+        assert_eq!(
+            response.headers().get::<Location>(),
+            Some(&Location::new("/sub/?sort=size"))
+        );
+
+        // Not a network-path reference to the host `sub`
+        let req = get_request("//sub");
+        let response = core.run(dispatch(&router, req, StatusCode::MovedPermanently))
+            .unwrap();
+        assert_eq!(
+            response.headers().get::<Location>(),
+            Some(&Location::new("/sub/"))
+        );
+    }
+
+    #[test]
+    fn test_dispatch_mounted_directory_redirect() {
+        let mut core = Core::new().unwrap();
+        let router: Rc<dyn Router> = Rc::new(
+            StaticRouterService::new(&core.handle(), get_root("mounted_redirect"))
+                .with_autoindex(true),
+        );
+        let mount = MountRouter::new("/files", vec![router]);
+
+        let ctx = RouteContext::new();
+        let req = get_request("/files/sub?sort=size");
+        let status_code = mount.route(&req, &ctx).wait().unwrap();
+        assert_eq!(status_code, StatusCode::MovedPermanently);
+        let response = core.run(mount.dispatch(req, status_code, &ctx)).unwrap();
+        assert_eq!(
+            response.headers().get::<Location>(),
+            Some(&Location::new("/files/sub/?sort=size"))
+        );
+    }
+
+    #[test]
+    fn test_dispatch_autoindex() {
+        let mut core = Core::new().unwrap();
+        let root = get_root("autoindex");
+        File::create(root.join("sub").join("big.bin"))
+            .unwrap()
+            .write_all(&[0; 100])
+            .unwrap();
+        let router = StaticRouterService::new(&core.handle(), root).with_autoindex(true);
+
+        assert_eq!(route(&router, &get_request("/sub/")), Ok(StatusCode::Ok));
+        let response = core.run(dispatch(&router, get_request("/sub/"), StatusCode::Ok))
+            .unwrap();
+        assert_eq!(
+            response.headers().get::<ContentType>(),
+            Some(&ContentType::html())
+        );
+        let body = core.run(response.body().concat2()).unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<a href=\"big.bin\">big.bin</a></td><td>100</td>"));
+        assert!(body.find("big.bin").unwrap() < body.find("data.json").unwrap());
+
+        let req = get_request("/sub/?format=json&sort=size&order=desc");
+        let response = core.run(dispatch(&router, req, StatusCode::Ok)).unwrap();
+        assert_eq!(
+            response.headers().get::<ContentType>(),
+            Some(&ContentType::json())
+        );
+        let body = core.run(response.body().concat2()).unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with(
+            "{\"path\":\"/sub/\",\"entries\":[{\"name\":\"big.bin\",\"type\":\"file\",\"size\":100,"
+        ));
+        assert!(body.find("big.bin").unwrap() < body.find("data.json").unwrap());
+
+        let req = get_request("/sub/?sort=size&order=asc");
+        let response = core.run(dispatch(&router, req, StatusCode::Ok)).unwrap();
+        let body = core.run(response.body().concat2()).unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.find("data.json").unwrap() < body.find("big.bin").unwrap());
+    }
+
+    #[test]
+    fn test_dispatch_autoindex_hidden() {
+        let mut core = Core::new().unwrap();
+        let root = get_root("autoindex_hidden");
+        File::create(root.join("sub").join(".env"))
+            .unwrap()
+            .write_all(b"SECRET=1")
+            .unwrap();
+
+        for &(hidden, listed) in &[(false, false), (true, true)] {
+            let router = StaticRouterService::new(&core.handle(), root.clone())
+                .with_autoindex(true)
+                .with_autoindex_hidden(hidden);
+            let response = core.run(dispatch(&router, get_request("/sub/"), StatusCode::Ok))
+                .unwrap();
+            let body = core.run(response.body().concat2()).unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains("data.json"));
+            assert_eq!(body.contains(".env"), listed, "hidden: {}", hidden);
+        }
+    }
+
+    #[test]
+    fn test_route_traversal() {
+        let core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("traversal"));
+
+        // Not found, so that the next routers get a chance to handle them
+        for path in &[
+            "/%ff.html",
+            "/sub/../index.html",
+            "/../static/traversal/index.html",
+            "/%2e%2e/traversal/index.html",
+            "/sub/%2E%2E/index.html",
+            "/sub%2f..%2findex.html",
+            "/sub%5c..%5cindex.html",
+            "/index.html%00.txt",
+            "/%2fetc%2fpasswd",
+        ] {
+            let status_code = route(&router, &get_request(path));
+            assert_eq!(status_code, Err(StatusCode::NotFound), "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    fn get_symlink_root(name: &str) -> PathBuf {
+        use std::os::unix::fs::symlink;
+        use std::fs::remove_file;
+
+        let root = get_root(name);
+        let outside = root.parent().unwrap().join(format!("{}-outside.txt", name));
+        File::create(&outside).unwrap().write_all(b"outside").unwrap();
+        for (link, target) in &[
+            (root.join("inside.html"), root.join("index.html")),
+            (root.join("outside.txt"), outside),
+        ] {
+            let _ = remove_file(link);
+            symlink(target, link).unwrap();
+        }
+        root
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_route_symlink_policies() {
+        let core = Core::new().unwrap();
+        let root = get_symlink_root("symlinks");
+        let expectations = vec![
+            (SymlinkPolicy::Follow, Ok(StatusCode::Ok), Ok(StatusCode::Ok)),
+            (
+                SymlinkPolicy::Deny,
+                Err(StatusCode::Forbidden),
+                Err(StatusCode::Forbidden),
+            ),
+            (
+                SymlinkPolicy::FollowWithinRoot,
+                Ok(StatusCode::Ok),
+                Err(StatusCode::Forbidden),
+            ),
+        ];
+
+        for (policy, inside, outside) in expectations {
+            let router = StaticRouterService::new(&core.handle(), root.clone())
+                .with_symlink_policy(policy);
+            let status_code = route(&router, &get_request("/inside.html"));
+            assert_eq!(status_code, inside, "{:?} inside", policy);
+            let status_code = route(&router, &get_request("/outside.txt"));
+            assert_eq!(status_code, outside, "{:?} outside", policy);
+            let status_code = route(&router, &get_request("/index.html"));
+            assert_eq!(status_code, Ok(StatusCode::Ok), "{:?} plain file", policy);
+        }
+    }
+
+    fn get_conditional_request(path: &str, header: IfNoneMatch) -> HyperRequest {
+        let mut req = get_request(path);
+        req.headers_mut().set(header);
+        req
+    }
+
+    fn get_etag(router: &StaticRouterService, path: &str) -> EntityTag {
+        let (path, metadata) = router.find_file(path).unwrap();
+        router.entity_tag(&path, &metadata).unwrap()
+    }
+
+    #[test]
+    fn test_route_if_none_match() {
+        let core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("if_none_match"));
+        let etag = get_etag(&router, "/index.html");
+        assert!(!etag.weak);
+
+        let req = get_conditional_request("/index.html", IfNoneMatch::Items(vec![etag.clone()]));
+        assert_eq!(route(&router, &req), Ok(StatusCode::NotModified));
+        let req = get_conditional_request("/index.html", IfNoneMatch::Any);
+        assert_eq!(route(&router, &req), Ok(StatusCode::NotModified));
+        let other = EntityTag::strong(String::from("other"));
+        let req = get_conditional_request("/index.html", IfNoneMatch::Items(vec![other]));
+        assert_eq!(route(&router, &req), Ok(StatusCode::Ok));
+
+        let mut req = get_conditional_request("/index.html", IfNoneMatch::Items(vec![etag]));
+        req.set_method(Method::Post);
+        assert_eq!(route(&router, &req), Ok(StatusCode::Ok));
+    }
+
+    #[test]
+    fn test_route_if_modified_since() {
+        let core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("if_modified_since"));
+        let (_, metadata) = router.find_file("/index.html").unwrap();
+        let modified = metadata.modified().unwrap();
+
+        let mut req = get_request("/index.html");
+        req.headers_mut()
+            .set(IfModifiedSince(HttpDate::from(modified)));
+        assert_eq!(route(&router, &req), Ok(StatusCode::NotModified));
+
+        let mut req = get_request("/index.html");
+        req.headers_mut().set(IfModifiedSince(HttpDate::from(UNIX_EPOCH)));
+        assert_eq!(route(&router, &req), Ok(StatusCode::Ok));
+
+        // If-None-Match takes precedence over If-Modified-Since
+        let other = EntityTag::strong(String::from("other"));
+        let mut req = get_conditional_request("/index.html", IfNoneMatch::Items(vec![other]));
+        req.headers_mut()
+            .set(IfModifiedSince(HttpDate::from(modified)));
+        assert_eq!(route(&router, &req), Ok(StatusCode::Ok));
+    }
+
+    #[test]
+    fn test_dispatch_not_modified() {
+        let mut core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("not_modified"));
+        let etag = get_etag(&router, "/index.html");
+
+        let req = get_conditional_request("/index.html", IfNoneMatch::Items(vec![etag.clone()]));
+        let response = core.run(dispatch(&router, req, StatusCode::NotModified))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NotModified);
+        assert_eq!(response.headers().get::<ETag>(), Some(&ETag(etag)));
+        assert!(response.headers().get::<LastModified>().is_some());
+        assert!(response.headers().get::<ContentLength>().is_none());
+        let body = core.run(response.body().concat2()).unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_etag_strategies() {
+        let core = Core::new().unwrap();
+        let root = get_root("etag_strategies");
+        let router = StaticRouterService::new(&core.handle(), root.clone())
+            .with_etag_strategy(ETagStrategy::WeakMetadata);
+        assert!(get_etag(&router, "/index.html").weak);
+
+        let router = StaticRouterService::new(&core.handle(), root.clone())
+            .with_etag_strategy(ETagStrategy::Disabled);
+        let (path, metadata) = router.find_file("/index.html").unwrap();
+        assert!(router.entity_tag(&path, &metadata).is_none());
+
+        let router = StaticRouterService::new(&core.handle(), root.clone())
+            .with_etag_strategy(ETagStrategy::ContentHash);
+        let index = get_etag(&router, "/index.html");
+        assert!(!index.weak);
+        assert_eq!(index, get_etag(&router, "/index.html"));
+        assert!(index.strong_ne(&get_etag(&router, "/sub/data.json")));
+    }
+
+    #[test]
+    fn test_dispatch_reuses_route() {
+        let mut core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("reuses_route"))
+            .with_etag_strategy(ETagStrategy::ContentHash);
+
+        let ctx = RouteContext::new();
+        let req = get_request("/index.html");
+        let status_code = router.route(&req, &ctx).wait().unwrap();
+        let response = core.run(router.dispatch(req, status_code, &ctx)).unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(response.headers().has::<ETag>());
+        // The content hash has been looked up once, while routing
+        assert_eq!(router.hash_clock.get(), 1);
+
+        // Without the context of `route`, the target is looked up again
+        let response = core.run(dispatch(&router, get_request("/index.html"), status_code));
+        assert!(response.unwrap().headers().has::<ETag>());
+        assert_eq!(router.hash_clock.get(), 2);
+    }
+
+    #[test]
+    fn test_content_hash_eviction() {
+        let core = Core::new().unwrap();
+        let root = get_root("hash_eviction");
+        create_dir_all(root.join("many")).unwrap();
+        for i in 0..MAX_CACHED_HASHES {
+            File::create(root.join("many").join(format!("{}.txt", i)))
+                .unwrap()
+                .write_all(i.to_string().as_bytes())
+                .unwrap();
+        }
+        let router = StaticRouterService::new(&core.handle(), root.clone())
+            .with_etag_strategy(ETagStrategy::ContentHash);
+        for i in 0..MAX_CACHED_HASHES {
+            get_etag(&router, &format!("/many/{}.txt", i));
+        }
+        get_etag(&router, "/many/0.txt");
+
+        // Every hash is valid: the least recently used one goes
+        get_etag(&router, "/index.html");
+        {
+            let hashes = router.hashes.borrow();
+            assert_eq!(hashes.len(), MAX_CACHED_HASHES);
+            assert!(hashes.contains_key(&root.join("many").join("0.txt")));
+            assert!(!hashes.contains_key(&root.join("many").join("1.txt")));
+        }
+
+        // Hashes of changed or removed files go first
+        File::create(root.join("many").join("2.txt"))
+            .unwrap()
+            .write_all(b"changed")
+            .unwrap();
+        remove_file(root.join("many").join("3.txt")).unwrap();
+        get_etag(&router, "/sub/data.json");
+        let hashes = router.hashes.borrow();
+        assert_eq!(hashes.len(), MAX_CACHED_HASHES - 1);
+        assert!(!hashes.contains_key(&root.join("many").join("2.txt")));
+        assert!(!hashes.contains_key(&root.join("many").join("3.txt")));
+        assert!(hashes.contains_key(&root.join("many").join("4.txt")));
+    }
+
+    #[test]
+    fn test_dispatch_range() {
+        let mut core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("range"));
+
+        let mut req = get_request("/index.html");
+        req.headers_mut().set(Range::bytes(4, 8));
+        let response = core.run(dispatch(&router, req, StatusCode::Ok)).unwrap();
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        assert_eq!(
+            response.headers().get::<ContentRange>(),
+            Some(&ContentRange(ContentRangeSpec::Bytes {
+                range: Some((4, 8)),
+                instance_length: Some(14),
+            }))
+        );
+        assert_eq!(
+            response.headers().get::<ContentLength>(),
+            Some(&ContentLength(5))
+        );
+        let body = core.run(response.body().concat2()).unwrap();
+        assert_eq!(&body[..], b"index");
+
+        let mut req = get_request("/index.html");
+        req.headers_mut().set(Range::bytes(100, 200));
+        let error = core.run(dispatch(&router, req, StatusCode::Ok)).err().unwrap();
+        assert_eq!(error.status_code, StatusCode::RangeNotSatisfiable);
+        assert_eq!(
+            error.headers.get::<ContentRange>(),
+            Some(&ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(14),
+            }))
+        );
+    }
+
+    fn get_encoded_request(path: &str, accept_encoding: &str) -> HyperRequest {
+        let mut req = get_request(path);
+        req.headers_mut()
+            .set_raw("Accept-Encoding", accept_encoding.to_owned());
+        req
+    }
+
+    fn get_encoded_body(
+        core: &mut Core,
+        router: &StaticRouterService,
+        req: HyperRequest,
+    ) -> (Option<ContentEncoding>, Vec<u8>) {
+        let response = core.run(dispatch(router, req, StatusCode::Ok)).unwrap();
+        assert_eq!(
+            response.headers().get::<ContentType>(),
+            Some(&ContentType::html())
+        );
+        let encoding = response.headers().get::<ContentEncoding>().cloned();
+        let body = core.run(response.body().concat2()).unwrap();
+        (encoding, body.to_vec())
+    }
+
+    #[test]
+    fn test_dispatch_precompressed() {
+        let mut core = Core::new().unwrap();
+        let root = get_root("precompressed");
+        File::create(root.join("index.html.br"))
+            .unwrap()
+            .write_all(b"brotli")
+            .unwrap();
+        File::create(root.join("index.html.gz"))
+            .unwrap()
+            .write_all(b"gzip")
+            .unwrap();
+        let router = StaticRouterService::new(&core.handle(), root);
+
+        let expectations = vec![
+            ("gzip, deflate, br", Some(Encoding::Brotli), &b"brotli"[..]),
+            ("gzip;q=1.0, br;q=0.5", Some(Encoding::Gzip), &b"gzip"[..]),
+            ("gzip", Some(Encoding::Gzip), &b"gzip"[..]),
+            ("*", Some(Encoding::Brotli), &b"brotli"[..]),
+            ("*, br;q=0", Some(Encoding::Gzip), &b"gzip"[..]),
+            ("identity", None, &b"<h1>index</h1>"[..]),
+        ];
+        for (accept_encoding, encoding, content) in expectations {
+            let req = get_encoded_request("/index.html", accept_encoding);
+            let (found_encoding, body) = get_encoded_body(&mut core, &router, req);
+            assert_eq!(
+                found_encoding,
+                encoding.map(|encoding| ContentEncoding(vec![encoding])),
+                "{}",
+                accept_encoding
+            );
+            assert_eq!(&body[..], content, "{}", accept_encoding);
+        }
+
+        let response = core.run(dispatch(&router, get_request("/index.html"), StatusCode::Ok))
+            .unwrap();
+        assert_eq!(
+            response.headers().get_raw("Vary").unwrap(),
+            "Accept-Encoding"
+        );
+        let plain_etag = response.headers().get::<ETag>().unwrap().0.clone();
+
+        // Every representation has its own validators
+        let req = get_encoded_request("/index.html", "br");
+        let response = core.run(dispatch(&router, req, StatusCode::Ok)).unwrap();
+        let brotli_etag = response.headers().get::<ETag>().unwrap().0.clone();
+        assert_ne!(plain_etag, brotli_etag);
+        let mut req = get_encoded_request("/index.html", "br");
+        req.headers_mut()
+            .set(IfNoneMatch::Items(vec![brotli_etag.clone()]));
+        assert_eq!(route(&router, &req), Ok(StatusCode::NotModified));
+        let mut req = get_request("/index.html");
+        req.headers_mut()
+            .set(IfNoneMatch::Items(vec![brotli_etag]));
+        assert_eq!(route(&router, &req), Ok(StatusCode::Ok));
+
+        let router = StaticRouterService::new(&core.handle(), get_root("precompressed"))
+            .with_precompressed(false);
+        let req = get_encoded_request("/index.html", "br");
+        let response = core.run(dispatch(&router, req, StatusCode::Ok)).unwrap();
+        assert!(response.headers().get_raw("Vary").is_none());
+        assert!(response.headers().get::<ContentEncoding>().is_none());
+
+        let req = get_encoded_request("/sub/data.json", "br");
+        let router = StaticRouterService::new(&core.handle(), get_root("precompressed"));
+        let response = core.run(dispatch(&router, req, StatusCode::Ok)).unwrap();
+        assert!(response.headers().get_raw("Vary").is_none());
+    }
+
+    #[test]
+    fn test_dispatch_traversal() {
+        let mut core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("dispatch_traversal"));
+
+        let error = core.run(dispatch(&router, get_request("/sub/../index.html"), StatusCode::Ok))
+            .err()
+            .unwrap();
+        assert_eq!(error.status_code, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn test_dispatch_io_error() {
+        let core = Core::new().unwrap();
+        let root = get_root("io_error");
+        let router = StaticRouterService::new(&core.handle(), root.clone()).with_autoindex(true);
+
+        let error = router
+            .list_directory(get_request("/gone/"), &root.join("gone"))
+            .err()
+            .unwrap();
+        assert_eq!(error.status_code, StatusCode::InternalServerError);
+        // The path is logged, but not shown to the client
+        assert_eq!(error.message(), None);
+        assert!(error.to_string().contains("gone"), "{}", error);
+    }
+
+    #[test]
+    fn test_file_chunk_stream() {
+        let path = get_root("chunks").join("large.bin");
+        File::create(&path)
+            .unwrap()
+            .write_all(&vec![b'x'; 2 * CHUNK_SIZE + 1])
+            .unwrap();
+        let source = FileSource {
+            file: File::open(&path).unwrap(),
+        };
+
+        let sizes: Vec<usize> = source
+            .stream_range(0, 2 * CHUNK_SIZE as u64)
+            .map(|chunk| chunk.len())
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(sizes, vec![CHUNK_SIZE, CHUNK_SIZE, 1]);
+        let sizes: Vec<usize> = source
+            .stream_range(10, CHUNK_SIZE as u64 + 9)
+            .map(|chunk| chunk.len())
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(sizes, vec![CHUNK_SIZE]);
+    }
+
+    #[test]
+    fn test_dispatch_file() {
+        let mut core = Core::new().unwrap();
+        let router = StaticRouterService::new(&core.handle(), get_root("dispatch"));
+
+        let response = core.run(dispatch(&router, get_request("/sub/data.json"), StatusCode::Ok))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(
+            response.headers().get::<ContentType>(),
+            Some(&ContentType::json())
+        );
+        assert_eq!(
+            response.headers().get::<ContentLength>(),
+            Some(&ContentLength(8))
+        );
+        assert!(response.headers().get::<LastModified>().is_some());
+        assert!(response.headers().get::<ETag>().is_some());
+
+        let body = core.run(response.body().concat2()).unwrap();
+        assert_eq!(&body[..], b"{\"a\": 1}");
+    }
 }
@@ -0,0 +1,255 @@
+use services::{ResponseFuture, RouterService};
+
+use futures::future::ok;
+use hyper::{Error as HyperError, StatusCode};
+use hyper::header::Host;
+use hyper::server::{Request as HyperRequest, Response as HyperResponse, Service as HyperService};
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// The `[server.virtual_hosts]` section of `http-server.toml`.
+///
+/// ```toml
+/// [server.virtual_hosts]
+/// default = "main"
+///
+/// [server.virtual_hosts.sites]
+/// main = ["example.com", "www.example.com"]
+/// blog = ["blog.example.com", "*.blog.example.com"]
+/// ```
+///
+/// `sites` maps site names to the host names they are served for, and `default` names the site
+/// serving requests for any other host. See [`VirtualHostService`](struct.VirtualHostService.html).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VirtualHostsConfig {
+    #[serde(default)]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub sites: BTreeMap<String, Vec<String>>,
+}
+
+/// A `VirtualHostService` serves several sites, each one handled by its own
+/// [`RouterService`](struct.RouterService.html), chosen from the host the request is sent to.
+///
+/// The host is taken from the authority of absolute request URIs, or else from the `Host`
+/// header. Ports and case are ignored. Host names are matched against:
+///
+/// - exact names, like `example.com`;
+/// - wildcard names, like `*.example.com`, matching any subdomain of `example.com` but not
+///   `example.com` itself. The longest matching wildcard wins.
+///
+/// Exact names take precedence over wildcards. Requests for other hosts are served by the
+/// default site if any, otherwise they are answered with an empty `NotFound` response.
+#[derive(Default)]
+pub struct VirtualHostService {
+    exact: BTreeMap<String, Rc<RouterService>>,
+    /// Wildcard names, stored as the suffix they match (e.g. `.example.com`)
+    wildcards: Vec<(String, Rc<RouterService>)>,
+    default: Option<Rc<RouterService>>,
+}
+
+impl VirtualHostService {
+    /// Creates a service without sites, answering every request with `NotFound`.
+    pub fn new() -> VirtualHostService {
+        VirtualHostService::default()
+    }
+
+    /// Builds the service described by `config`. `new_site` is called once for every site
+    /// name, including the default one, and returns the `RouterService` serving it.
+    pub fn from_config<F>(config: &VirtualHostsConfig, mut new_site: F) -> VirtualHostService
+    where
+        F: FnMut(&str) -> RouterService,
+    {
+        let mut service = VirtualHostService::new();
+        let mut sites: BTreeMap<&str, Rc<RouterService>> = BTreeMap::new();
+        for (name, hosts) in &config.sites {
+            let site = Rc::new(new_site(name));
+            for host in hosts {
+                service = service.with_host(host, &site);
+            }
+            sites.insert(name, site);
+        }
+        if let Some(ref name) = config.default {
+            let site = match sites.get(name.as_str()) {
+                Some(site) => Rc::clone(site),
+                None => Rc::new(new_site(name)),
+            };
+            service = service.with_default(&site);
+        }
+        service
+    }
+
+    /// Serves `site` for `host`, which is either an exact host name or a wildcard such as
+    /// `*.example.com`.
+    pub fn with_host(mut self, host: &str, site: &Rc<RouterService>) -> VirtualHostService {
+        let host = normalize(host);
+        match host.strip_prefix('*') {
+            Some(suffix) => {
+                self.wildcards.push((suffix.to_owned(), Rc::clone(site)));
+                self.wildcards
+                    .sort_by_key(|wildcard| Reverse(wildcard.0.len()));
+            }
+            None => {
+                self.exact.insert(host, Rc::clone(site));
+            }
+        }
+        self
+    }
+
+    /// Serves `site` for the hosts matching no other name.
+    pub fn with_default(mut self, site: &Rc<RouterService>) -> VirtualHostService {
+        self.default = Some(Rc::clone(site));
+        self
+    }
+
+    /// Returns the site serving `req`.
+    fn site(&self, req: &HyperRequest) -> Option<&Rc<RouterService>> {
+        let host = req.uri()
+            .host()
+            .map(normalize)
+            .or_else(|| req.headers().get::<Host>().map(|host| normalize(host.hostname())));
+        host.and_then(|host| {
+            self.exact.get(&host).or_else(|| {
+                self.wildcards
+                    .iter()
+                    .find(|&(suffix, _)| host.ends_with(suffix.as_str()))
+                    .map(|(_, site)| site)
+            })
+        }).or(self.default.as_ref())
+    }
+}
+
+/// Lowercases a host name and removes its trailing dot, if any.
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+impl HyperService for VirtualHostService {
+    type Request = HyperRequest;
+    type Response = HyperResponse;
+    type Error = HyperError;
+    type Future = ResponseFuture;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        match self.site(&req) {
+            Some(site) => site.call(req),
+            None => {
+                debug!("no site for {:?}", req.headers().get::<Host>());
+                Box::new(ok(HyperResponse::new().with_status(StatusCode::NotFound)))
+            }
+        }
+    }
+}
+
+//========================== TESTS =====================================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use HttpError;
+    use futures::{Future, Stream};
+    use hyper::Method;
+    use services::{ErrorHandler, PatternRouter};
+    use std::str::from_utf8;
+    use toml;
+
+    struct NameErrorHandler;
+
+    impl ErrorHandler for NameErrorHandler {
+        fn dispatch(&self, error: HttpError) -> ResponseFuture {
+            Box::new(ok(HyperResponse::new().with_status(error.status_code)))
+        }
+    }
+
+    fn get_site(name: &str) -> RouterService {
+        let name = name.to_owned();
+        let error_handler: Rc<dyn ErrorHandler> = Rc::new(NameErrorHandler);
+        let router = PatternRouter::new("/*path", move |_req, _params| {
+            Box::new(ok(HyperResponse::new().with_body(name.clone())))
+        });
+        RouterService::new(vec![Rc::new(router)], &error_handler)
+    }
+
+    fn get_body(
+        service: &VirtualHostService,
+        uri: &str,
+        host: Option<&str>,
+    ) -> (StatusCode, String) {
+        let mut req = HyperRequest::new(Method::Get, uri.parse().unwrap());
+        if let Some(host) = host {
+            req.headers_mut().set_raw("Host", host.to_owned());
+        }
+        let response = service.call(req).wait().unwrap();
+        let status_code = response.status();
+        let body = response.body().concat2().wait().unwrap();
+        (status_code, from_utf8(&body).unwrap().to_owned())
+    }
+
+    fn get_config() -> VirtualHostsConfig {
+        toml::from_str(
+            r#"
+            default = "main"
+
+            [sites]
+            main = ["example.com", "www.example.com"]
+            blog = ["blog.example.com", "*.example.com"]
+            team = ["*.team.example.com"]
+            "#,
+        ).unwrap()
+    }
+
+    #[test]
+    fn test_from_config() {
+        let mut built = Vec::new();
+        let service = VirtualHostService::from_config(&get_config(), |name| {
+            built.push(name.to_owned());
+            get_site(name)
+        });
+        assert_eq!(built, vec!["blog", "main", "team"]);
+
+        for (host, site) in [
+            ("example.com", "main"),
+            ("WWW.Example.com.:8080", "main"),
+            ("blog.example.com", "blog"),
+            ("alice.example.com", "blog"),
+            ("a.team.example.com", "team"),
+            ("team.example.com", "blog"),
+            ("example.org", "main"),
+        ] {
+            assert_eq!(
+                get_body(&service, "/index.html", Some(host)),
+                (StatusCode::Ok, String::from(site)),
+                "{}",
+                host
+            );
+        }
+        // The authority of absolute URIs wins over the Host header
+        assert_eq!(
+            get_body(&service, "http://blog.example.com/", Some("example.com")),
+            (StatusCode::Ok, String::from("blog"))
+        );
+        assert_eq!(
+            get_body(&service, "/", None),
+            (StatusCode::Ok, String::from("main"))
+        );
+    }
+
+    #[test]
+    fn test_no_default() {
+        let site = Rc::new(get_site("main"));
+        let service = VirtualHostService::new().with_host("example.com", &site);
+        assert_eq!(
+            get_body(&service, "/", Some("example.com")),
+            (StatusCode::Ok, String::from("main"))
+        );
+        assert_eq!(
+            get_body(&service, "/", Some("example.org")),
+            (StatusCode::NotFound, String::new())
+        );
+        assert_eq!(
+            get_body(&service, "/", None),
+            (StatusCode::NotFound, String::new())
+        );
+    }
+}
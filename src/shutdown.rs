@@ -0,0 +1,130 @@
+use futures::prelude::*;
+use futures::task::AtomicTask;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{Builder as ThreadBuilder, JoinHandle};
+
+use signal_hook::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use reload::ConfigHandle;
+
+struct Shutdown {
+    requested: AtomicBool,
+    task: AtomicTask,
+    local_addr: Mutex<Option<SocketAddr>>,
+}
+
+/// A `ShutdownHandle` is used to stop a running [`RssHttpServer`](struct.RssHttpServer.html) from
+/// any thread. It also tells the address the server listens on.
+///
+/// Once shutdown is requested the server stops accepting connections and lets in-flight requests
+/// complete, waiting at most `shutdown_timeout` seconds before exiting.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<Shutdown>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> ShutdownHandle {
+        ShutdownHandle {
+            inner: Arc::new(Shutdown {
+                requested: AtomicBool::new(false),
+                task: AtomicTask::new(),
+                local_addr: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Requests the server to shut down. Calling it more than once has no further effect.
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        self.inner.task.notify();
+    }
+
+    /// Returns `true` if shutdown has been requested.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Returns the address the server listens on, once it has started. With `bind_port = 0`,
+    /// this tells the port picked by the system.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.inner
+            .local_addr
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records the address the server listens on.
+    pub(crate) fn set_local_addr(&self, addr: SocketAddr) {
+        *self.inner
+            .local_addr
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(addr);
+    }
+
+    /// Returns a future that resolves once shutdown has been requested.
+    pub(crate) fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// Future resolving when [`ShutdownHandle::shutdown`] is called.
+pub(crate) struct ShutdownSignal {
+    inner: Arc<Shutdown>,
+}
+
+impl Future for ShutdownSignal {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.inner.task.register();
+        if self.inner.requested.load(Ordering::SeqCst) {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Background thread that turns SIGTERM and SIGINT into a shutdown request, and SIGHUP into a
+/// configuration reload.
+pub(crate) struct SignalWatcher {
+    signals: Signals,
+    thread: JoinHandle<()>,
+}
+
+impl SignalWatcher {
+    pub fn new(shutdown: ShutdownHandle, config: ConfigHandle) -> io::Result<SignalWatcher> {
+        let signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+        let thread_signals = signals.clone();
+        let thread = ThreadBuilder::new()
+            .name(String::from("rss-server-signals"))
+            .spawn(move || {
+                for signal in thread_signals.forever() {
+                    if signal == SIGHUP {
+                        info!("received SIGHUP, reloading the configuration");
+                        config.reload_or_log();
+                    } else {
+                        info!("received signal {}, shutting down", signal);
+                        shutdown.shutdown();
+                        break;
+                    }
+                }
+            })?;
+        Ok(SignalWatcher { signals, thread })
+    }
+
+    /// Unregisters the signal handlers and waits for the watcher thread to exit.
+    pub fn stop(self) {
+        self.signals.close();
+        let _ = self.thread.join();
+    }
+}
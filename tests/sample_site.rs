@@ -9,9 +9,9 @@ use hyper::server::{Request as HyperRequest, Response as HyperResponse};
 use hyper::Error as HyperError;
 use hyper::header::ContentLength;
 use hyper::StatusCode;
-use rss_server::{ErrorHandler, HttpError, Router, RouterService};
+use rss_server::{ErrorHandler, HttpError, RouteContext, Router, RouterService};
 
-pub type ResponseFuture = Box<Future<Item = HyperResponse, Error = HyperError>>;
+pub type ResponseFuture = Box<dyn Future<Item = HyperResponse, Error = HyperError>>;
 
 struct SampleRouter {
     content: String,
@@ -42,7 +42,11 @@ impl ErrorHandler for SampleErrorHandler {
 }
 
 impl Router for SampleRouter {
-    fn route(&self, req: &HyperRequest) -> Box<Future<Item = StatusCode, Error = StatusCode>> {
+    fn route(
+        &self,
+        req: &HyperRequest,
+        _ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
         if self.path == req.path() {
             Box::new(ok(StatusCode::Ok))
         } else {
@@ -53,7 +57,8 @@ impl Router for SampleRouter {
         &self,
         _req: HyperRequest,
         _status_code: StatusCode,
-    ) -> Box<Future<Item = HyperResponse, Error = HttpError>> {
+        _ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
         let content = self.content.clone();
         let res = HyperResponse::new()
             .with_header(ContentLength(content.len() as u64))
@@ -62,20 +67,22 @@ impl Router for SampleRouter {
     }
 }
 
-fn get_routers() -> Vec<Rc<Router>> {
+fn get_routers() -> Vec<Rc<dyn Router>> {
     let route1 = Rc::new(SampleRouter::new("/page1", "page1"));
     let route2 = Rc::new(SampleRouter::new("/page2", "page2"));
     let route3 = Rc::new(SampleRouter::new("/page3", "page3"));
-    let mut v_routes: Vec<Rc<Router>> = Vec::new();
-    v_routes.push(route1);
-    v_routes.push(route2);
-    v_routes.push(route3);
-    v_routes
+    vec![route1, route2, route3]
 }
 
 pub fn get_site_service() -> RouterService {
-    let routes = get_routers();
-    let error_handler: Rc<ErrorHandler> = Rc::new(SampleErrorHandler {});
+    get_site_service_with(Vec::new())
+}
+
+/// Same as `get_site_service`, with `extra` routers tried before the sample pages.
+pub fn get_site_service_with(extra: Vec<Rc<dyn Router>>) -> RouterService {
+    let mut routes = extra;
+    routes.extend(get_routers());
+    let error_handler: Rc<dyn ErrorHandler> = Rc::new(SampleErrorHandler {});
 
     RouterService::new(routes, &error_handler)
 }
@@ -18,9 +18,19 @@ use hyper::Error;
 // use std::str::from_utf8;
 use std::vec::Vec;
 use futures::future::ok;
+use std::fs::{create_dir_all, File};
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use hyper::server::{Request as HyperRequest, Response as HyperResponse};
+use hyper::header::ContentLength;
+use tokio_core::reactor::Timeout;
+use rss_server::{HttpError, HttpServer, RouteContext, Router, RssHttpServer, ShutdownHandle,
+                 VirtualHostService};
 
 mod sample_site;
-use sample_site::get_site_service;
+use sample_site::{get_site_service, get_site_service_with};
 
 // use sample_site;
 
@@ -42,6 +52,127 @@ fn serve(shutdown_rx: FutureReceiver<bool>) -> std::net::SocketAddr {
     addr_rx.recv().unwrap()
 }
 
+/// Router answering `/slow` after a delay, used to check that in-flight requests are drained.
+struct SlowRouter {
+    handle: Handle,
+}
+
+impl Router for SlowRouter {
+    fn route(
+        &self,
+        req: &HyperRequest,
+        _ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+        if req.path() == "/slow" {
+            Box::new(ok(StatusCode::Ok))
+        } else {
+            Box::new(futures::future::err(StatusCode::NotFound))
+        }
+    }
+
+    fn dispatch(
+        &self,
+        _req: HyperRequest,
+        _status_code: StatusCode,
+        _ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+        let timeout = Timeout::new(Duration::from_millis(500), &self.handle).unwrap();
+        Box::new(timeout.then(|_| {
+            let content = "slow";
+            ok(HyperResponse::new()
+                .with_header(ContentLength(content.len() as u64))
+                .with_body(content))
+        }))
+    }
+}
+
+/// Router answering `/site` with the name of the site it belongs to.
+struct SiteRouter {
+    name: String,
+}
+
+impl Router for SiteRouter {
+    fn route(
+        &self,
+        req: &HyperRequest,
+        _ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = StatusCode, Error = StatusCode>> {
+        if req.path() == "/site" {
+            Box::new(ok(StatusCode::Ok))
+        } else {
+            Box::new(futures::future::err(StatusCode::NotFound))
+        }
+    }
+
+    fn dispatch(
+        &self,
+        _req: HyperRequest,
+        _status_code: StatusCode,
+        _ctx: &RouteContext,
+    ) -> Box<dyn Future<Item = HyperResponse, Error = HttpError>> {
+        let content = self.name.clone();
+        Box::new(ok(HyperResponse::new()
+            .with_header(ContentLength(content.len() as u64))
+            .with_body(content)))
+    }
+}
+
+/// Waits until the server of `shutdown` listens, and returns its port.
+fn wait_for_port(shutdown: &ShutdownHandle) -> u16 {
+    for _ in 0..50 {
+        if let Some(addr) = shutdown.local_addr() {
+            return addr.port();
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("RssHttpServer did not start");
+}
+
+/// Writes an `http-server.toml` under `tests/out/<name>` and starts an `RssHttpServer` with it,
+/// on a free port.
+fn serve_rss(name: &str, num_workers: usize) -> (ShutdownHandle, thread::JoinHandle<()>, u16) {
+    let conf_dir: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "out", name]
+        .iter()
+        .collect();
+    create_dir_all(&conf_dir).unwrap();
+    let mut file = File::create(conf_dir.join("http-server.toml")).unwrap();
+    write!(
+        file,
+        "bind_address = \"127.0.0.1\"\nbind_port = 0\nnum_workers = {}\n\
+         shutdown_timeout = 5\nhandle_signals = false\n",
+        num_workers
+    ).unwrap();
+
+    let server = RssHttpServer::new(conf_dir);
+    let shutdown = server.shutdown_handle();
+    let join = thread::Builder::new()
+        .name(format!("rss-server-{}", name))
+        .spawn(move || {
+            server
+                .start(|handle: &Handle| {
+                    let slow: Rc<dyn Router> = Rc::new(SlowRouter {
+                        handle: handle.clone(),
+                    });
+                    get_site_service_with(vec![slow])
+                })
+                .unwrap();
+        })
+        .unwrap();
+
+    let port = wait_for_port(&shutdown);
+    (shutdown, join, port)
+}
+
+fn get_body(core: &mut Core, port: u16, page: &str) -> (StatusCode, String) {
+    let handle = core.handle();
+    core.run(do_get(&handle, port, page).and_then(|res| {
+        let status = res.status();
+        res.body().concat2().map(move |body| {
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        })
+    })).unwrap()
+}
+
 fn do_get(handle: &Handle, port: u16, path: &str) -> FutureResponse {
     let client = Client::new(handle);
     let uri = format!("http://localhost:{}/{}", port, path)
@@ -137,3 +268,96 @@ fn test_page_multiple_resources() {
         ("page1", StatusCode::Ok, "page1"),
     ]);
 }
+
+#[test]
+fn test_rss_server_workers() {
+    let (shutdown, join, port) = serve_rss("workers", 2);
+
+    let mut core = Core::new().unwrap();
+    for page in &["page1", "page2", "page3", "page1"] {
+        let (status, body) = get_body(&mut core, port, page);
+        assert_eq!(status, StatusCode::Ok);
+        assert_eq!(body, *page);
+    }
+    let (status, _) = get_body(&mut core, port, "notAValidPage");
+    assert_eq!(status, StatusCode::NotFound);
+
+    shutdown.shutdown();
+    join.join().unwrap();
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn test_rss_server_drains_on_shutdown() {
+    let (shutdown, join, port) = serve_rss("drain", 1);
+
+    let delayed_shutdown = shutdown.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        delayed_shutdown.shutdown();
+    });
+
+    let mut core = Core::new().unwrap();
+    let (status, body) = get_body(&mut core, port, "slow");
+    assert!(shutdown.is_shutdown());
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "slow");
+
+    join.join().unwrap();
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn test_rss_server_reloads_config() {
+    let conf_dir: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "out", "reload_server"]
+        .iter()
+        .collect();
+    create_dir_all(&conf_dir).unwrap();
+    let write_config = |default: &str| {
+        let mut file = File::create(conf_dir.join("http-server.toml")).unwrap();
+        write!(
+            file,
+            "bind_address = \"127.0.0.1\"\nbind_port = 0\nnum_workers = 1\n\
+             handle_signals = false\n[virtual_hosts]\ndefault = \"{}\"\n",
+            default
+        ).unwrap();
+    };
+    write_config("main");
+
+    let server = RssHttpServer::new(conf_dir.clone());
+    let shutdown = server.shutdown_handle();
+    let config = server.config_handle();
+    let join = thread::spawn(move || {
+        let config = server.config_handle();
+        server
+            .start(move |_handle: &Handle| {
+                VirtualHostService::from_config(&config.virtual_hosts(), |name| {
+                    let site: Rc<dyn Router> = Rc::new(SiteRouter {
+                        name: name.to_owned(),
+                    });
+                    get_site_service_with(vec![site])
+                })
+            })
+            .unwrap();
+    });
+    let port = wait_for_port(&shutdown);
+
+    let mut core = Core::new().unwrap();
+    assert_eq!(get_body(&mut core, port, "site").1, "main");
+
+    write_config("blog");
+    config.reload().unwrap();
+    assert_eq!(config.generation(), 1);
+    assert_eq!(get_body(&mut core, port, "site").1, "blog");
+
+    // An invalid configuration is rejected, the server keeps the current one
+    File::create(conf_dir.join("http-server.toml"))
+        .unwrap()
+        .write_all(b"bind_port = \"http\"\n")
+        .unwrap();
+    assert!(config.reload().is_err());
+    assert_eq!(get_body(&mut core, port, "site").1, "blog");
+
+    shutdown.shutdown();
+    join.join().unwrap();
+}